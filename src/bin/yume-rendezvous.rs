@@ -0,0 +1,39 @@
+use std::{
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use structopt::StructOpt;
+use yume::{
+    rendezvous::{serve, REGISTRATION_TIMEOUT, RENDEZVOUS_PORT},
+    socket,
+};
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "Rendezvous server introducing yume peers to each other")]
+struct Opt {
+    #[structopt(long, help = "address to listen on, [::]:3003 by default")]
+    address: Option<SocketAddr>,
+    #[structopt(
+        long,
+        help = "seconds after which peers which stopped registering are forgotten"
+    )]
+    registration_timeout: Option<u64>,
+}
+
+#[async_std::main]
+async fn main() -> std::io::Result<()> {
+    let opt = Opt::from_args();
+    // The unspecified IPv6 address makes for a dual-stack socket.
+    let address = opt
+        .address
+        .unwrap_or_else(|| SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), RENDEZVOUS_PORT));
+    let socket = socket::bind(address)?;
+
+    println!("Listening on {}", socket.local_addr()?);
+
+    serve(
+        socket,
+        Duration::from_secs(opt.registration_timeout.unwrap_or(REGISTRATION_TIMEOUT)),
+    )
+    .await
+}
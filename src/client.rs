@@ -1,39 +1,53 @@
-use async_std::{io, net::UdpSocket};
+use async_std::{future, task};
 use crossterm::{
     cursor,
     event::{self, Event, KeyCode, KeyEvent},
-    execute, queue,
+    execute, queue, style,
     style::Print,
     terminal,
 };
+use futures::future::join_all;
 use std::{
     io::{stdout, Write},
+    net::SocketAddr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::{
-    config::{BUFFER_SIZE, CLIENT_PORT, SERVER_PORT, TIMEOUT},
-    error::throw,
+    config::MAX_TIMEOUT,
+    envelope::Payload,
+    error::{throw, warn, Error},
+    fragment::split,
     help::render as render_help,
     io::Line,
-    key::Key,
-    message::Message,
+    network::Network,
     peers::Peers,
+    presence::State,
+    server::{display_above, display_state},
+    settings::Settings,
     terminal::println,
-    types::SenderReceiver,
-    utils::get_content_from_buffer,
+    types::{SenderReceiver, SharedNetwork, SharedPresence, SharedSessions},
+    utils::generate_random_id,
 };
 
-/// Starts the UDP client based on a tuple of peers and a crypto key.
-pub async fn start(peers: Arc<Peers>, key: Arc<Key>, sender_receiver: SenderReceiver) {
-    let mut characters = String::new();
+/// Starts the UDP client based on the remote peers and their crypto sessions.
+pub async fn start(
+    network: SharedNetwork,
+    settings: Arc<Settings>,
+    peers: Arc<Peers>,
+    sessions: SharedSessions,
+    presence: SharedPresence,
+    sender_receiver: SenderReceiver,
+) {
+    let mut characters = Zeroizing::new(String::new());
 
     loop {
-        let shared_characters = Arc::new(characters.clone());
         let sender_receiver = sender_receiver.clone();
 
-        match event::read().unwrap() {
+        // Reading events blocks, the receive loop must keep running meanwhile.
+        match task::spawn_blocking(event::read).await.unwrap() {
             Event::Mouse(_) => (),
             Event::Resize(_, _) => {
                 execute!(stdout(), terminal::LeaveAlternateScreen).unwrap();
@@ -42,12 +56,107 @@ pub async fn start(peers: Arc<Peers>, key: Arc<Key>, sender_receiver: SenderRece
                 break;
             }
             Event::Key(KeyEvent { code, .. }) => {
+                presence.lock().await.touch(Instant::now());
+
                 match code {
                     KeyCode::Enter => {
+                        // Push a noop value in the channel, nothing is being typed anymore.
+                        if !sender_receiver.1.is_empty() {
+                            let _ = sender_receiver.1.recv().await;
+                        }
+                        sender_receiver.0.send(None).await;
+
                         if characters.starts_with('/') {
                             match characters.as_str() {
                                 "/help" => render_help().await,
+                                "/peers" => {
+                                    let presence = presence.lock().await;
+
+                                    execute!(
+                                        stdout(),
+                                        terminal::Clear(terminal::ClearType::CurrentLine),
+                                        cursor::MoveToColumn(0)
+                                    )
+                                    .unwrap();
+
+                                    for remote in peers.remotes.iter() {
+                                        if let Some(state) = presence.state(*remote) {
+                                            peers.display_remote(*remote);
+                                            state.render();
+                                            println("", false);
+                                        }
+                                    }
+                                }
+                                "/verify" => {
+                                    let sessions = sessions.lock().await;
+
+                                    for remote in peers.remotes.iter() {
+                                        let fingerprint = &sessions[remote].fingerprint;
+
+                                        if peers.is_group() {
+                                            println(format!("{}:", peers.name(*remote)), true);
+                                        }
+
+                                        println(
+                                            format!("Fingerprint: {}", fingerprint.to_hex()),
+                                            true,
+                                        );
+                                        println(
+                                            format!("Words: {}", fingerprint.to_words()),
+                                            false,
+                                        );
+                                    }
+                                }
+                                "/ping" => {
+                                    let id = generate_random_id();
+                                    let now = Instant::now();
+                                    let pongs = join_all(peers.remotes.iter().map(|remote| {
+                                        let (network, sessions, settings) =
+                                            (&network, &sessions, &settings);
+
+                                        async move {
+                                            request(
+                                                network,
+                                                *remote,
+                                                sessions,
+                                                Payload::Ping(id),
+                                                id,
+                                                settings,
+                                            )
+                                            .await
+                                            .map(|_| (*remote, now.elapsed()))
+                                        }
+                                    }))
+                                    .await;
+
+                                    for pong in pongs {
+                                        match pong {
+                                            Ok((remote, elapsed)) if peers.is_group() => println(
+                                                format!(
+                                                    "Pong from {} in {} ms",
+                                                    peers.name(remote),
+                                                    elapsed.as_millis()
+                                                ),
+                                                true,
+                                            ),
+                                            Ok((_, elapsed)) => println(
+                                                format!("Pong in {} ms", elapsed.as_millis()),
+                                                true,
+                                            ),
+                                            Err(error) => warn(error),
+                                        }
+                                    }
+                                }
                                 "/quit" => {
+                                    // Let the peers know, they might not be listening anymore.
+                                    let _ = notify(
+                                        &network,
+                                        &peers.remotes,
+                                        &sessions,
+                                        Payload::Disconnect,
+                                    )
+                                    .await;
+
                                     execute!(stdout(), terminal::LeaveAlternateScreen).unwrap();
                                     terminal::disable_raw_mode().unwrap();
                                     break;
@@ -57,7 +166,7 @@ pub async fn start(peers: Arc<Peers>, key: Arc<Key>, sender_receiver: SenderRece
                         } else {
                             let mut stdout = stdout();
 
-                            for line in 0..Line::get_content_lines_length(characters) {
+                            for line in 0..Line::get_content_lines_length(&characters) {
                                 queue!(stdout, terminal::Clear(terminal::ClearType::CurrentLine),)
                                     .unwrap();
 
@@ -66,34 +175,52 @@ pub async fn start(peers: Arc<Peers>, key: Arc<Key>, sender_receiver: SenderRece
                                 }
                             }
 
-                            // Push a noop value in the channel.
-                            if !sender_receiver.1.is_empty() {
-                                let _ = sender_receiver.1.recv().await;
-                            }
-                            sender_receiver.0.send(None).await;
+                            let content = Arc::new(Zeroizing::new(characters.to_string()));
 
-                            // Send message.
-                            send_message(
+                            Status::Pending.render(&content);
+
+                            // Send message, several of them being delivered at once.
+                            task::spawn(send_message(
+                                Arc::clone(&network),
+                                Arc::clone(&settings),
                                 Arc::clone(&peers),
-                                Arc::clone(&shared_characters),
-                                Arc::clone(&key),
-                            )
-                            .await;
+                                content,
+                                Arc::clone(&sessions),
+                                sender_receiver.clone(),
+                            ));
                         }
 
-                        // Reset afterwards.
-                        characters = String::new();
+                        // Reset afterwards, wiping what was typed.
+                        characters.zeroize();
                     }
                     KeyCode::Char(character) => {
+                        // Let the peers know that a new message is being typed,
+                        // unreachable ones wouldn't get it anyway.
+                        if characters.is_empty() && character != '/' {
+                            let remotes: Vec<SocketAddr> = {
+                                let presence = presence.lock().await;
+
+                                peers
+                                    .remotes
+                                    .iter()
+                                    .filter(|remote| {
+                                        !matches!(
+                                            presence.state(**remote),
+                                            Some(State::Unreachable) | Some(State::Left)
+                                        )
+                                    })
+                                    .copied()
+                                    .collect()
+                            };
+                            let _ = notify(&network, &remotes, &sessions, Payload::Typing).await;
+                        }
+
                         characters.push(character);
 
                         if !sender_receiver.1.is_empty() {
                             let _ = sender_receiver.1.recv().await;
                         }
-                        sender_receiver
-                            .0
-                            .send(Some(Line::new(characters.clone())))
-                            .await;
+                        sender_receiver.0.send(Some(Line::new(&characters))).await;
 
                         execute!(stdout(), Print(character)).unwrap();
                     }
@@ -103,14 +230,11 @@ pub async fn start(peers: Arc<Peers>, key: Arc<Key>, sender_receiver: SenderRece
                         if !sender_receiver.1.is_empty() {
                             let _ = sender_receiver.1.recv().await;
                         }
-                        sender_receiver
-                            .0
-                            .send(Some(Line::new(characters.clone())))
-                            .await;
+                        sender_receiver.0.send(Some(Line::new(&characters))).await;
 
                         let mut stdout = stdout();
 
-                        for line in 0..Line::get_content_lines_length(characters.clone()) {
+                        for line in 0..Line::get_content_lines_length(&characters) {
                             queue!(stdout, terminal::Clear(terminal::ClearType::CurrentLine),)
                                 .unwrap();
 
@@ -123,7 +247,7 @@ pub async fn start(peers: Arc<Peers>, key: Arc<Key>, sender_receiver: SenderRece
                             stdout,
                             cursor::MoveToColumn(0),
                             terminal::Clear(terminal::ClearType::CurrentLine),
-                            Print(characters.clone())
+                            Print(characters.as_str())
                         )
                         .unwrap();
 
@@ -137,72 +261,204 @@ pub async fn start(peers: Arc<Peers>, key: Arc<Key>, sender_receiver: SenderRece
     }
 }
 
-/// Send an UDP message to the remote peer.
-pub async fn send_message(peers: Arc<Peers>, content: Arc<String>, key: Arc<Key>) {
-    let cloned_key = key.clone();
-    let message = Message::new(content.to_string(), cloned_key);
-
-    match UdpSocket::bind([peers.local.as_str(), ":", &CLIENT_PORT.to_string()].join("")).await {
-        Ok(socket) => {
-            match socket
-                .send_to(
-                    message.serialize().as_bytes(),
-                    [peers.remote.as_str(), ":", &SERVER_PORT.to_string()].join(""),
-                )
-                .await
-            {
-                Ok(_) => {
-                    let mut buffer = vec![0u8; BUFFER_SIZE];
-
-                    execute!(
-                        stdout(),
-                        cursor::Hide,
-                        terminal::Clear(terminal::ClearType::CurrentLine),
-                        cursor::MoveToColumn(0),
-                        Print("Sending message..."),
-                        cursor::MoveToColumn(0),
-                    )
-                    .unwrap();
-
-                    match io::timeout(Duration::from_secs(TIMEOUT), async {
-                        socket.recv_from(&mut buffer).await
-                    })
-                    .await
-                    {
-                        Ok(received) => {
-                            let number_of_bytes = received.0;
-
-                            match Message::deserialize(get_content_from_buffer(
-                                &buffer,
-                                number_of_bytes,
-                            )) {
-                                Ok(message) => {
-                                    execute!(
-                                        stdout(),
-                                        terminal::Clear(terminal::ClearType::CurrentLine),
-                                        cursor::MoveToColumn(0),
-                                        Print(message.decrypt(key.clone())),
-                                        Print("\n"),
-                                        cursor::MoveToColumn(0),
-                                        cursor::Show,
-                                    )
-                                    .unwrap();
-                                }
-                                Err(_) => {
-                                    execute!(stdout(), cursor::Show,).unwrap();
-                                    throw(401);
-                                }
-                            }
-                        }
-                        Err(_) => {
-                            execute!(stdout(), cursor::Show,).unwrap();
-                            throw(201);
-                        }
-                    }
-                }
-                Err(_) => throw(202),
-            }
+/// Delivery status of a sent text message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Delivered,
+    Failed,
+    /// Delivered to some of the remote peers of a group only.
+    Partial(usize, usize),
+    Pending,
+}
+
+impl Status {
+    fn render(self, content: &str) {
+        let (label, color) = match self {
+            Status::Delivered => (String::from("delivered"), style::Color::DarkGreen),
+            Status::Failed => (String::from("failed"), style::Color::DarkRed),
+            Status::Partial(delivered, total) => (
+                format!("delivered to {}/{}", delivered, total),
+                style::Color::DarkYellow,
+            ),
+            Status::Pending => (String::from("pending"), style::Color::DarkGrey),
+        };
+        let mut stdout = stdout();
+
+        queue!(
+            stdout,
+            terminal::Clear(terminal::ClearType::CurrentLine),
+            cursor::MoveToColumn(0),
+            Print(content),
+            style::SetForegroundColor(color),
+            Print(format!(" ({})", label)),
+            style::SetForegroundColor(style::Color::Reset),
+        )
+        .unwrap();
+
+        queue!(stdout, Print("\n"), cursor::MoveToColumn(0)).unwrap();
+        stdout.flush().unwrap();
+    }
+}
+
+/// Sends a text message to every remote peer until its delivery is
+/// acknowledged, displaying its status above the line being typed once
+/// known.
+pub async fn send_message(
+    network: SharedNetwork,
+    settings: Arc<Settings>,
+    peers: Arc<Peers>,
+    content: Arc<Zeroizing<String>>,
+    sessions: SharedSessions,
+    sender_receiver: SenderReceiver,
+) {
+    let id = generate_random_id();
+
+    let results = join_all(peers.remotes.iter().map(|remote| {
+        request(
+            &network,
+            *remote,
+            &sessions,
+            Payload::text(&content, id),
+            id,
+            &settings,
+        )
+    }))
+    .await;
+    let delivered = results.iter().filter(|result| result.is_ok()).count();
+
+    let status = match delivered {
+        0 => Status::Failed,
+        delivered if delivered == results.len() => Status::Delivered,
+        delivered => Status::Partial(delivered, results.len()),
+    };
+
+    display_above(&sender_receiver, || {
+        status.render(&content);
+
+        for error in results.into_iter().filter_map(Result::err) {
+            warn(error);
+        }
+    })
+    .await;
+}
+
+/// Sends a heartbeat to every remote peer which didn't leave at each interval,
+/// telling whether the user is idle, and displays the peers which stopped
+/// sending theirs as unreachable.
+pub async fn keep_alive(
+    network: SharedNetwork,
+    settings: Arc<Settings>,
+    peers: Arc<Peers>,
+    sessions: SharedSessions,
+    presence: SharedPresence,
+    sender_receiver: SenderReceiver,
+) {
+    loop {
+        let (remotes, is_idle) = {
+            let presence = presence.lock().await;
+            let remotes: Vec<SocketAddr> = peers
+                .remotes
+                .iter()
+                .filter(|remote| presence.state(**remote) != Some(State::Left))
+                .copied()
+                .collect();
+
+            (remotes, presence.is_idle(Instant::now()))
+        };
+
+        // Unreachable peers might come back, failures are expected.
+        join_all(
+            remotes.iter().map(|remote| {
+                transmit(&network, *remote, &sessions, Payload::Heartbeat { is_idle })
+            }),
+        )
+        .await;
+
+        task::sleep(settings.heartbeat_interval).await;
+
+        let expired = presence.lock().await.expire(Instant::now());
+
+        for remote in expired {
+            display_state(&peers, remote, &sender_receiver, State::Unreachable).await;
+        }
+    }
+}
+
+/// Sends a payload to the given remote peers without waiting for any reply.
+async fn notify(
+    network: &Network,
+    remotes: &[SocketAddr],
+    sessions: &SharedSessions,
+    payload: Payload,
+) -> Result<(), Error> {
+    join_all(
+        remotes
+            .iter()
+            .map(|remote| transmit(network, *remote, sessions, payload.clone())),
+    )
+    .await
+    .into_iter()
+    .collect()
+}
+
+/// Sends a payload to a remote peer until the reply with the given id is
+/// handed over by the receive loop, the time waited for it doubling after
+/// every attempt.
+async fn request(
+    network: &Network,
+    remote: SocketAddr,
+    sessions: &SharedSessions,
+    payload: Payload,
+    id: u64,
+    settings: &Settings,
+) -> Result<(), Error> {
+    let receiver = network.wait(remote, id).await;
+    let mut timeout = settings.retransmission_timeout;
+    let mut result = Err(Error::from(201));
+
+    for _ in 0..settings.max_attempts {
+        if let Err(error) = transmit(network, remote, sessions, payload.clone()).await {
+            result = Err(error);
+
+            break;
+        }
+
+        if future::timeout(timeout, receiver.recv()).await.is_ok() {
+            result = Ok(());
+
+            break;
         }
-        Err(error) => eprintln!("{}", error),
+
+        // Backing off, up to a point.
+        timeout = (timeout * 2).min(Duration::from_secs(MAX_TIMEOUT));
+    }
+
+    network.forget(remote, id).await;
+
+    result
+}
+
+/// Seals a payload, fragmented if too large, and sends it via UDP to a
+/// remote peer.
+async fn transmit(
+    network: &Network,
+    remote: SocketAddr,
+    sessions: &SharedSessions,
+    payload: Payload,
+) -> Result<(), Error> {
+    for payload in split(payload).map_err(Error::from)? {
+        let message = sessions
+            .lock()
+            .await
+            .get_mut(&remote)
+            .map(|session| session.seal(&payload))
+            .ok_or_else(|| Error::from(202))?;
+
+        network
+            .send_to(&message.to_bytes(), remote)
+            .await
+            .map_err(Error::from)?;
     }
+
+    Ok(())
 }
@@ -1,7 +1,37 @@
-pub const BUFFER_SIZE: usize = 4096;
-pub const CLIENT_PORT: i32 = 3000;
+use std::net::Ipv6Addr;
+
+pub const ARGON2_ITERATIONS: u32 = 2;
+pub const ARGON2_MEMORY: u32 = 19456;
+pub const ARGON2_PARALLELISM: u32 = 1;
+pub const BUFFER_SIZE: usize = 16384;
+pub const CONFIG_PATH: &str = ".yume/config.json";
+pub const DEFAULT_NICKNAME: &str = "anonymous";
 pub const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
+pub const DISCOVERY_BUFFER_SIZE: usize = 1024;
+pub const DISCOVERY_DURATION: u64 = 5;
+// Link-local scope, the last groups spelling "yume".
+pub const DISCOVERY_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x7975, 0x6d65);
+pub const DISCOVERY_INTERVAL: u64 = 1;
+pub const DISCOVERY_PORT: u16 = 3002;
+pub const FRAGMENT_SIZE: usize = 1000;
+pub const FRAGMENT_TIMEOUT: u64 = 30;
+pub const HANDSHAKE_ATTEMPTS: u64 = 60;
+pub const HANDSHAKE_INTERVAL: u64 = 1;
+pub const HEARTBEAT_INTERVAL: u64 = 5;
+pub const IDLE_TIMEOUT: u64 = 120;
+pub const KEYRING_PATH: &str = ".yume/keyring";
+pub const MAX_ATTEMPTS: u32 = 4;
+pub const MAX_BUFFER_SIZE: usize = 65_535;
+pub const MAX_NICKNAME_LENGTH: usize = 32;
+pub const MAX_DELIVERED_IDS: usize = 1000;
+pub const MAX_REASSEMBLY_SIZE: usize = 1_048_576;
+pub const MAX_SKIPPED_KEYS: usize = 1000;
+pub const MAX_SKIPPED_MESSAGES: u64 = 1000;
+pub const MAX_TIMEOUT: u64 = 3600;
+pub const MISSED_HEARTBEATS: u32 = 3;
+pub const MIN_BUFFER_SIZE: usize = 4096;
 pub const NONCE_LENGTH: usize = 12;
-pub const SERVER_PORT: i32 = 3001;
-pub const TIMEOUT: u64 = 5;
+pub const PROTOCOL_VERSION: u8 = 3;
+pub const RETRANSMISSION_TIMEOUT: u64 = 1;
+pub const SERVER_PORT: u16 = 3001;
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
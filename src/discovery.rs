@@ -0,0 +1,320 @@
+use async_std::{io, net::UdpSocket, task};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{
+    fmt,
+    net::{SocketAddr, SocketAddrV6},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{
+        DISCOVERY_BUFFER_SIZE, DISCOVERY_DURATION, DISCOVERY_GROUP, DISCOVERY_INTERVAL,
+        DISCOVERY_PORT, MAX_NICKNAME_LENGTH,
+    },
+    terminal::{println, prompt},
+    utils::generate_random_id,
+};
+
+const MAX_FINGERPRINT_LENGTH: usize = 64;
+
+/// Announcement multicast on the local link by a peer in discovery mode.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Announcement {
+    /// Random id telling apart the instances running on the same host.
+    pub id: u64,
+    pub nickname: String,
+    /// Fingerprint of the pre-shared key, if any.
+    pub fingerprint: Option<String>,
+    /// Port the peer listens on for the conversation.
+    pub port: u16,
+}
+
+impl Announcement {
+    pub fn new(nickname: &str, fingerprint: Option<String>, port: u16) -> Self {
+        Announcement {
+            id: generate_random_id(),
+            nickname: sanitize(nickname, MAX_NICKNAME_LENGTH),
+            fingerprint,
+            port,
+        }
+    }
+}
+
+/// Peer found on the local link.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiscoveredPeer {
+    pub address: SocketAddr,
+    pub nickname: String,
+    pub fingerprint: Option<String>,
+}
+
+impl DiscoveredPeer {
+    /// The peer is reached on the address its announcement came from, along
+    /// with the zone ID of the link, and on the announced port.
+    fn new(origin: SocketAddr, announcement: Announcement) -> Self {
+        let address = match origin {
+            SocketAddr::V6(origin) => SocketAddr::V6(SocketAddrV6::new(
+                *origin.ip(),
+                announcement.port,
+                0,
+                origin.scope_id(),
+            )),
+            origin => SocketAddr::new(origin.ip(), announcement.port),
+        };
+
+        DiscoveredPeer {
+            address,
+            nickname: sanitize(&announcement.nickname, MAX_NICKNAME_LENGTH),
+            fingerprint: announcement
+                .fingerprint
+                .map(|fingerprint| sanitize(&fingerprint, MAX_FINGERPRINT_LENGTH)),
+        }
+    }
+}
+
+impl fmt::Display for DiscoveredPeer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.nickname, self.address)?;
+
+        match &self.fingerprint {
+            Some(fingerprint) => write!(f, ", key fingerprint {}", fingerprint),
+            None => write!(f, ", no key fingerprint"),
+        }
+    }
+}
+
+/// Multicast socket shared by all the instances running on the same host.
+pub struct Discovery {
+    group: SocketAddr,
+    socket: UdpSocket,
+}
+
+impl Discovery {
+    /// Joins the discovery group on the given interface, 0 letting the system
+    /// pick it.
+    pub fn bind(port: u16, interface: u32) -> Result<Self, u16> {
+        let bind = || -> io::Result<Socket> {
+            let socket = Socket::new(Domain::ipv6(), Type::dgram(), Some(Protocol::udp()))?;
+
+            socket.set_only_v6(true)?;
+            socket.set_reuse_address(true)?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            socket.bind(&SockAddr::from(SocketAddrV6::new(
+                "::".parse().unwrap(),
+                port,
+                0,
+                0,
+            )))?;
+            socket.join_multicast_v6(&DISCOVERY_GROUP, interface)?;
+            socket.set_multicast_if_v6(interface)?;
+            // Other instances running on the same host have to hear us.
+            socket.set_multicast_loop_v6(true)?;
+
+            Ok(socket)
+        };
+        let socket = bind().map_err(|_| 206_u16)?;
+        let port = socket
+            .local_addr()
+            .ok()
+            .and_then(|address| address.as_inet6())
+            .map_or(port, |address| address.port());
+
+        Ok(Discovery {
+            group: SocketAddr::V6(SocketAddrV6::new(DISCOVERY_GROUP, port, 0, interface)),
+            socket: UdpSocket::from(socket.into_udp_socket()),
+        })
+    }
+
+    pub async fn announce(&self, announcement: &Announcement) -> Result<(), u16> {
+        let bytes = serde_json::to_vec(announcement).map_err(|_| 202_u16)?;
+
+        self.socket
+            .send_to(&bytes, self.group)
+            .await
+            .map(|_| ())
+            .map_err(|_| 202)
+    }
+
+    /// Announces ourselves at every interval and gathers the announcements of
+    /// the other peers until the duration elapses.
+    pub async fn discover(
+        &self,
+        announcement: &Announcement,
+        duration: Duration,
+        interval: Duration,
+    ) -> Result<Vec<DiscoveredPeer>, u16> {
+        let deadline = Instant::now() + duration;
+        let mut buffer = [0; DISCOVERY_BUFFER_SIZE];
+        let mut peers: Vec<DiscoveredPeer> = vec![];
+
+        while Instant::now() < deadline {
+            self.announce(announcement).await?;
+
+            let next_announcement = deadline.min(Instant::now() + interval);
+
+            while let Some(timeout) = next_announcement.checked_duration_since(Instant::now()) {
+                let (number_of_bytes, origin) =
+                    match io::timeout(timeout, self.socket.recv_from(&mut buffer)).await {
+                        Ok(received) => received,
+                        Err(_) => break,
+                    };
+
+                // Our own announcements are looped back, anything else
+                // sent to the group is ignored.
+                let peer = match serde_json::from_slice::<Announcement>(&buffer[..number_of_bytes])
+                {
+                    Ok(other) if other.id != announcement.id => DiscoveredPeer::new(origin, other),
+                    _ => continue,
+                };
+
+                match peers.iter_mut().find(|known| known.address == peer.address) {
+                    Some(known) => *known = peer,
+                    None => peers.push(peer),
+                }
+            }
+        }
+
+        Ok(peers)
+    }
+}
+
+/// Lists the peers discovered on the local link and lets the user pick the
+/// ones to chat with, several of them only in group mode.
+pub async fn select_peers(
+    announcement: Announcement,
+    interface: u32,
+    is_group: bool,
+) -> Result<Vec<String>, u16> {
+    let discovery = Arc::new(Discovery::bind(DISCOVERY_PORT, interface)?);
+    let interval = Duration::from_secs(DISCOVERY_INTERVAL);
+
+    println(String::from("Looking for peers on the local link..."), true);
+
+    let peers = discovery
+        .discover(
+            &announcement,
+            Duration::from_secs(DISCOVERY_DURATION),
+            interval,
+        )
+        .await?;
+
+    if peers.is_empty() {
+        return Err(207);
+    }
+
+    for (index, peer) in peers.iter().enumerate() {
+        println(format!("{}. {}", index + 1, peer), true);
+    }
+
+    // Keep announcing ourselves while the user picks, the other peers might
+    // still be looking for us.
+    let cloned_discovery = discovery.clone();
+    let announcer = task::spawn(async move {
+        loop {
+            let _ = cloned_discovery.announce(&announcement).await;
+
+            task::sleep(interval).await;
+        }
+    });
+    let selection = prompt(Some(String::from(
+        "Enter the number of the peer to chat with, e.g. 1 or 1,3 in group mode:",
+    )));
+
+    announcer.cancel().await;
+
+    let indexes = parse_selection(&selection.map_err(|_| 301_u16)?, peers.len())?;
+
+    if indexes.len() > 1 && !is_group {
+        return Err(610);
+    }
+
+    Ok(indexes
+        .into_iter()
+        .map(|index| peers[index].address.to_string())
+        .collect())
+}
+
+/// Parses a selection of discovered peers such as "2" or "1, 3".
+pub fn parse_selection(selection: &str, number_of_peers: usize) -> Result<Vec<usize>, u16> {
+    let mut indexes = vec![];
+
+    for number in selection.split(',').map(str::trim) {
+        match number.parse::<usize>() {
+            Ok(number) if (1..=number_of_peers).contains(&number) => {
+                if !indexes.contains(&(number - 1)) {
+                    indexes.push(number - 1);
+                }
+            }
+            _ => return Err(611),
+        }
+    }
+
+    Ok(indexes)
+}
+
+/// Nicknames and fingerprints come from anyone on the link, control
+/// characters must not reach the terminal.
+fn sanitize(text: &str, max_length: usize) -> String {
+    text.chars()
+        .filter(|character| !character.is_control())
+        .take(max_length)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peers::get_zone_index;
+    use async_std::task;
+
+    #[test]
+    fn test_discovery() {
+        task::block_on(async {
+            let alice_announcement = Announcement::new("alice", None, 3001);
+            // Linux loopback only handles multicast once enabled with
+            // `ip link set lo multicast on`, the default interface looping
+            // datagrams back to the same host otherwise.
+            let mut interfaces = get_zone_index("lo").into_iter().chain(Some(0));
+            let (alice, interface) = loop {
+                let interface = interfaces.next().unwrap();
+                let alice = Discovery::bind(0, interface).unwrap();
+
+                if alice.announce(&alice_announcement).await.is_ok() {
+                    break (alice, interface);
+                }
+            };
+            let port = alice.group.port();
+            let bob = Discovery::bind(port, interface).unwrap();
+            let bob_announcement =
+                Announcement::new("bob\u{1b}[2J", Some(String::from("ABCD")), 4001);
+            let (duration, interval) = (Duration::from_millis(500), Duration::from_millis(100));
+            let (alice_peers, bob_peers) = futures::join!(
+                alice.discover(&alice_announcement, duration, interval),
+                bob.discover(&bob_announcement, duration, interval)
+            );
+            let (alice_peers, bob_peers) = (alice_peers.unwrap(), bob_peers.unwrap());
+
+            // Both instances should only see each other, once.
+            assert_eq!(alice_peers.len(), 1);
+            assert_eq!(alice_peers[0].nickname, "bob[2J");
+            assert_eq!(alice_peers[0].address.port(), 4001);
+            assert_eq!(alice_peers[0].fingerprint, Some(String::from("ABCD")));
+            assert_eq!(bob_peers.len(), 1);
+            assert_eq!(bob_peers[0].nickname, "alice");
+            assert_eq!(bob_peers[0].address.port(), 3001);
+        });
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        assert_eq!(parse_selection("2", 3), Ok(vec![1]));
+        assert_eq!(parse_selection(" 3, 1,3 ", 3), Ok(vec![2, 0]));
+
+        for selection in &["", "0", "4", "1,", "foo"] {
+            assert_eq!(parse_selection(selection, 3), Err(611));
+        }
+    }
+}
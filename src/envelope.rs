@@ -0,0 +1,165 @@
+use std::{convert::TryInto, str};
+use zeroize::Zeroizing;
+
+use crate::{
+    fragment::Fragment,
+    handshake::Handshake,
+    message::{Kind, Message},
+    utils::get_content_from_buffer,
+};
+
+const ID_LENGTH: usize = 8;
+
+/// Every datagram exchanged between both peers: key exchanges are sent in
+/// clear, anything else is sealed within a message.
+#[derive(Debug, PartialEq)]
+pub enum Envelope {
+    KeyExchange(Handshake),
+    Message(Message),
+}
+
+impl Envelope {
+    pub fn decode(bytes: &[u8], json_compatibility: bool) -> Result<Self, u16> {
+        Message::decode(bytes, json_compatibility)
+            .map(Envelope::Message)
+            .or_else(|code| {
+                Handshake::deserialize(get_content_from_buffer(bytes, bytes.len()))
+                    .map(Envelope::KeyExchange)
+                    .map_err(|_| code)
+            })
+    }
+}
+
+/// Sealed content of a message, its kind being carried by the authenticated
+/// header.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+    /// Acknowledges the delivery of the text message with the given id.
+    Ack(u64),
+    Disconnect,
+    Fragment(Fragment),
+    /// Keeps the session alive, telling whether the user of the peer is idle.
+    Heartbeat {
+        is_idle: bool,
+    },
+    /// Checks that the peer is reachable, the pong carrying the same id.
+    Ping(u64),
+    Pong(u64),
+    /// Text message, its id staying the same across retransmissions.
+    Text {
+        content: Zeroizing<String>,
+        id: u64,
+    },
+    Typing,
+}
+
+impl Payload {
+    pub fn text(content: &str, id: u64) -> Self {
+        Payload::Text {
+            content: Zeroizing::new(String::from(content)),
+            id,
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self {
+            Payload::Ack(_) => Kind::Ack,
+            Payload::Disconnect => Kind::Disconnect,
+            Payload::Fragment(_) => Kind::Fragment,
+            Payload::Heartbeat { .. } => Kind::Heartbeat,
+            Payload::Ping(_) => Kind::Ping,
+            Payload::Pong(_) => Kind::Pong,
+            Payload::Text { .. } => Kind::Text,
+            Payload::Typing => Kind::Typing,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(match self {
+            Payload::Ack(id) | Payload::Ping(id) | Payload::Pong(id) => id.to_be_bytes().to_vec(),
+            Payload::Fragment(fragment) => fragment.to_bytes(),
+            Payload::Heartbeat { is_idle } => vec![u8::from(*is_idle)],
+            Payload::Text { content, id } => [&id.to_be_bytes(), content.as_bytes()].concat(),
+            _ => vec![],
+        })
+    }
+
+    pub fn from_bytes(kind: Kind, bytes: &[u8]) -> Result<Self, u16> {
+        match kind {
+            Kind::Ack | Kind::Ping | Kind::Pong => {
+                let id = u64::from_be_bytes(bytes.try_into().map_err(|_| 401_u16)?);
+
+                Ok(match kind {
+                    Kind::Ack => Payload::Ack(id),
+                    Kind::Ping => Payload::Ping(id),
+                    _ => Payload::Pong(id),
+                })
+            }
+            Kind::Fragment => Fragment::from_bytes(bytes).map(Payload::Fragment),
+            Kind::Heartbeat => match bytes {
+                [0] => Ok(Payload::Heartbeat { is_idle: false }),
+                [1] => Ok(Payload::Heartbeat { is_idle: true }),
+                _ => Err(401),
+            },
+            Kind::Text if bytes.len() < ID_LENGTH => Err(401),
+            Kind::Text => {
+                let (id, content) = bytes.split_at(ID_LENGTH);
+
+                str::from_utf8(content)
+                    .map(|content| {
+                        Payload::text(content, u64::from_be_bytes(id.try_into().unwrap()))
+                    })
+                    .map_err(|_| 405)
+            }
+            _ if !bytes.is_empty() => Err(401),
+            Kind::Disconnect => Ok(Payload::Disconnect),
+            Kind::Typing => Ok(Payload::Typing),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload() {
+        let payloads = vec![
+            Payload::Ack(42),
+            Payload::Disconnect,
+            Payload::Heartbeat { is_idle: false },
+            Payload::Heartbeat { is_idle: true },
+            Payload::Ping(42),
+            Payload::Pong(42),
+            Payload::text("foo", 42),
+            Payload::Typing,
+        ];
+
+        for payload in payloads {
+            assert_eq!(
+                Payload::from_bytes(payload.kind(), &payload.to_bytes()),
+                Ok(payload)
+            );
+        }
+
+        assert_eq!(Payload::from_bytes(Kind::Ack, &[0; 4]), Err(401));
+        assert_eq!(Payload::from_bytes(Kind::Ping, b"foo"), Err(401));
+        assert_eq!(Payload::from_bytes(Kind::Heartbeat, &[2]), Err(401));
+        assert_eq!(Payload::from_bytes(Kind::Text, &[0; 4]), Err(401));
+        assert_eq!(
+            Payload::from_bytes(Kind::Text, &[0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xfe]),
+            Err(405)
+        );
+    }
+
+    #[test]
+    fn test_envelope() {
+        let handshake = Handshake::new(vec![1, 2, 3], None);
+
+        assert_eq!(
+            Envelope::decode(handshake.serialize().as_bytes(), false),
+            Ok(Envelope::KeyExchange(handshake))
+        );
+        assert_eq!(Envelope::decode(b"foo", false), Err(401));
+    }
+}
@@ -2,10 +2,14 @@ use crate::terminal::println;
 
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, PartialEq)]
 pub enum Error {
+    #[error("Config error: {0}!")]
+    Config(String),
     #[error("Crypto error: {0}!")]
     Crypto(String),
+    #[error("Keyring error: {0}!")]
+    Keyring(String),
     #[error("Message error: {0}!")]
     Message(String),
     #[error("Network error: {0}!")]
@@ -21,20 +25,73 @@ fn _throw(code: u16) -> Error {
         // Crypto errors:
         101 => Error::Crypto(String::from("can't verify message signature")),
         102 => Error::Crypto(String::from("can't decode key")),
+        103 => Error::Crypto(String::from("can't authenticate peer")),
+        104 => Error::Crypto(String::from("can't derive session key")),
+        105 => Error::Crypto(String::from("message key is no longer available")),
+        106 => Error::Crypto(String::from("can't derive key from passphrase")),
+        107 => Error::Crypto(String::from("invalid key derivation parameters")),
+        108 => Error::Crypto(String::from("can't decrypt message")),
+        109 => Error::Crypto(String::from("pre-shared key fingerprint not confirmed")),
+        110 => Error::Crypto(String::from("pairwise group keys require a pre-shared key")),
         // Network errors:
         201 => Error::Network(String::from("timeout, can't connect to peer")),
         202 => Error::Network(String::from("message not sent")),
+        203 => Error::Network(String::from("key exchange failed, can't reach peer")),
+        204 => Error::Network(String::from("no route to peer")),
+        205 => Error::Network(String::from("can't bind local address")),
+        206 => Error::Network(String::from("can't join discovery group")),
+        207 => Error::Network(String::from("no peer discovered")),
+        208 => Error::Network(String::from("can't reach rendezvous server")),
+        209 => Error::Network(String::from("no peer showed up at the rendezvous")),
         // Stdin errors:
         301 => Error::Stdin(String::from("can't read from command line")),
         302 => Error::Stdin(String::from("resizing is unsupported")),
         // Message errors:
         401 => Error::Message(String::from("can't deserialize message")),
+        402 => Error::Message(String::from("replayed or outdated message rejected")),
+        403 => Error::Message(String::from("message metadata mismatch")),
+        404 => Error::Message(String::from("unsupported protocol version")),
+        405 => Error::Message(String::from("message content is not valid UTF-8")),
+        406 => Error::Message(String::from("invalid message padding")),
+        408 => Error::Message(String::from("invalid message fragment")),
+        409 => Error::Message(String::from("fragmented message too large, dropped")),
+        410 => Error::Message(String::from("message too large to be sent")),
+        // Keyring errors:
+        501 => Error::Keyring(String::from("can't read keyring")),
+        502 => Error::Keyring(String::from("can't write keyring")),
+        503 => Error::Keyring(String::from("can't unlock keyring, wrong passphrase")),
+        504 => Error::Keyring(String::from("unknown contact")),
+        505 => Error::Keyring(String::from("contact already exists")),
+        506 => Error::Keyring(String::from("passphrases don't match")),
+        // Config errors:
+        601 => Error::Config(String::from("can't read config file")),
+        602 => Error::Config(String::from("can't parse config file")),
+        603 => Error::Config(String::from("invalid peer address")),
+        604 => Error::Config(String::from("buffer size out of range")),
+        605 => Error::Config(String::from("invalid port")),
+        606 => Error::Config(String::from("timeouts and attempts must be positive")),
+        607 => Error::Config(String::from("can't resolve peer address")),
+        608 => Error::Config(String::from(
+            "remote peer can't be reached from local address",
+        )),
+        609 => Error::Config(String::from("duplicated peer address")),
+        610 => Error::Config(String::from("too many peer addresses, see --group")),
+        611 => Error::Config(String::from("invalid peer selection")),
+        612 => Error::Config(String::from("unknown network interface")),
+        613 => Error::Config(String::from("timeouts can't exceed an hour")),
         _ => Error::Unknown,
     }
 }
 
+impl From<u16> for Error {
+    fn from(code: u16) -> Self { _throw(code) }
+}
+
 pub fn throw(code: u16) { println(_throw(code).to_string(), true); }
 
+/// Displays a recoverable error, the conversation going on.
+pub fn warn(error: Error) { println(format!("Warning: {}", error), true); }
+
 #[cfg(test)]
 mod utils {
     use super::*;
@@ -46,6 +103,11 @@ mod utils {
             String::from("Crypto error: can't verify message signature!")
         );
 
+        assert_eq!(
+            _throw(402).to_string(),
+            String::from("Message error: replayed or outdated message rejected!")
+        );
+
         assert_eq!(_throw(999).to_string(), String::from("Unknown error!"));
     }
 }
@@ -0,0 +1,115 @@
+use ring::digest;
+
+use crate::key::Key;
+
+const FINGERPRINT_LABEL: &[u8] = b"yume fingerprint";
+const HEX_GROUPS: usize = 8;
+const NUMBER_OF_WORDS: usize = 6;
+const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adult", "agent", "alarm", "album", "alley", "amber", "angel",
+    "ankle", "apple", "apron", "arena", "armor", "arrow", "atlas", "attic", "audio", "autumn",
+    "axis", "bacon", "badge", "bagel", "baker", "bamboo", "banjo", "barrel", "basil", "basket",
+    "beach", "beacon", "beard", "beaver", "bell", "berry", "bishop", "blanket", "boat", "bonus",
+    "border", "bottle", "boxer", "bread", "brick", "bridge", "broom", "bucket", "butter", "cabin",
+    "cactus", "camera", "canal", "candle", "canoe", "canyon", "carbon", "carpet", "castle", "cat",
+    "cedar", "cello", "chalk", "cherry", "chess", "circle", "citrus", "clock", "cloud", "clover",
+    "coffee", "comet", "copper", "coral", "cotton", "cowboy", "crane", "crater", "crayon", "cup",
+    "dagger", "daisy", "dancer", "delta", "desert", "dinner", "doctor", "donkey", "dragon", "drum",
+    "eagle", "echo", "elbow", "engine", "falcon", "feather", "fern", "fiddle", "finger", "flag",
+    "flame", "flute", "forest", "fossil", "fox", "galaxy", "garden", "garlic", "geyser", "ginger",
+    "globe", "goat", "gold", "grape", "guitar", "hammer", "harbor", "harp", "hawk", "helmet",
+    "hero", "honey", "hornet", "hotel", "iceberg", "igloo", "island", "ivory", "jacket", "jaguar",
+    "jelly", "jewel", "jungle", "kayak", "kettle", "kitten", "kiwi", "koala", "ladder", "lagoon",
+    "lamp", "lantern", "laser", "lemon", "lily", "lion", "lizard", "magnet", "mango", "maple",
+    "marble", "meadow", "melon", "meteor", "mint", "mirror", "monkey", "moon", "mosaic", "muffin",
+    "nectar", "needle", "nest", "noodle", "oasis", "ocean", "olive", "onion", "orange", "orbit",
+    "otter", "owl", "oyster", "paddle", "palace", "panda", "parrot", "peach", "pearl", "pebble",
+    "pencil", "pepper", "piano", "pilot", "pine", "planet", "plum", "pocket", "pony", "potato",
+    "puzzle", "quartz", "quilt", "rabbit", "radar", "radio", "rain", "raven", "ribbon", "river",
+    "robot", "rocket", "rose", "ruby", "saddle", "salmon", "sandal", "satin", "saturn", "scarf",
+    "shadow", "shark", "shell", "silver", "sketch", "sled", "snow", "socket", "spider", "spoon",
+    "squid", "star", "stone", "sugar", "summer", "sunset", "swan", "tablet", "tango", "teapot",
+    "temple", "tiger", "tomato", "torch", "tulip", "tunnel", "turtle", "valley", "velvet",
+    "violin", "waffle", "walnut", "walrus", "whale", "wheat", "willow", "window", "winter",
+    "wizard", "wolf", "yacht", "yogurt", "zebra", "zenith", "zipper", "zodiac",
+];
+
+/// Digest of a key that both peers can compare out-of-band.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fingerprint([u8; digest::SHA256_OUTPUT_LEN]);
+
+impl Fingerprint {
+    pub fn new(key: &Key) -> Self {
+        let mut context = digest::Context::new(&digest::SHA256);
+        let mut value = [0; digest::SHA256_OUTPUT_LEN];
+
+        context.update(FINGERPRINT_LABEL);
+        context.update(&key.value);
+        value.copy_from_slice(context.finish().as_ref());
+
+        Fingerprint(value)
+    }
+
+    /// Renders the fingerprint as groups of four hexadecimal digits.
+    pub fn to_hex(&self) -> String {
+        self.0
+            .chunks(2)
+            .take(HEX_GROUPS)
+            .map(|group| format!("{:02X}{:02X}", group[0], group[1]))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// Renders the fingerprint as a short list of words, easier to read out
+    /// loud.
+    pub fn to_words(&self) -> String {
+        self.0
+            .iter()
+            .take(NUMBER_OF_WORDS)
+            .map(|byte| WORDS[*byte as usize])
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        let mut value = [0; digest::SHA512_OUTPUT_LEN];
+
+        for (index, byte) in value.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+
+        let fingerprint = Fingerprint::new(&Key::new(Some(value)));
+
+        // The fingerprint of a given key should never change.
+        assert_eq!(
+            fingerprint.to_hex(),
+            "8E9B 5A52 B469 6BD5 6405 B12B 6DE5 2229"
+        );
+        assert_eq!(
+            fingerprint.to_words(),
+            "laser mirror eagle dancer pencil galaxy"
+        );
+        assert_eq!(fingerprint, Fingerprint::new(&Key::new(Some(value))));
+
+        value[0] = 1;
+
+        assert_ne!(fingerprint, Fingerprint::new(&Key::new(Some(value))));
+    }
+
+    #[test]
+    fn test_fingerprint_words() {
+        let mut words = WORDS.to_vec();
+
+        words.sort();
+        words.dedup();
+
+        // Every byte should map to a distinct word.
+        assert_eq!(words.len(), WORDS.len());
+    }
+}
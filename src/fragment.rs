@@ -0,0 +1,309 @@
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    time::{Duration, Instant},
+};
+use zeroize::Zeroizing;
+
+use crate::{
+    config::{FRAGMENT_SIZE, FRAGMENT_TIMEOUT, MAX_REASSEMBLY_SIZE},
+    envelope::Payload,
+    message::Kind,
+    utils::generate_random_id,
+};
+
+const HEADER_LENGTH: usize = 13;
+
+/// Numbered chunk of a payload too large for a single datagram, sealed like
+/// any other message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment {
+    pub chunk: Zeroizing<Vec<u8>>,
+    pub count: u16,
+    pub id: u64,
+    pub index: u16,
+    pub kind: Kind,
+}
+
+impl Fragment {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.chunk.len());
+
+        bytes.push(self.kind as u8);
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(&self.count.to_be_bytes());
+        bytes.extend_from_slice(&self.chunk);
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, u16> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(408);
+        }
+
+        Ok(Fragment {
+            chunk: Zeroizing::new(bytes[HEADER_LENGTH..].to_vec()),
+            count: u16::from_be_bytes(bytes[11..13].try_into().unwrap()),
+            id: u64::from_be_bytes(bytes[1..9].try_into().unwrap()),
+            index: u16::from_be_bytes(bytes[9..11].try_into().unwrap()),
+            kind: Kind::from_u8(bytes[0]).map_err(|_| 408_u16)?,
+        })
+    }
+}
+
+/// Splits a payload into fragments if it doesn't fit in a single datagram.
+/// Payloads the remote peer wouldn't be able to put back together aren't
+/// sent at all.
+pub fn split(payload: Payload) -> Result<Vec<Payload>, u16> {
+    let bytes = payload.to_bytes();
+
+    if bytes.len() <= FRAGMENT_SIZE {
+        return Ok(vec![payload]);
+    }
+
+    if bytes.len() > MAX_REASSEMBLY_SIZE {
+        return Err(410);
+    }
+
+    // Retransmitted fragments have to fill the gaps left by the previous
+    // attempts.
+    let id = match payload {
+        Payload::Text { id, .. } => id,
+        _ => generate_random_id(),
+    };
+    let chunks = bytes.chunks(FRAGMENT_SIZE);
+    let count = chunks.len() as u16;
+
+    Ok(chunks
+        .enumerate()
+        .map(|(index, chunk)| {
+            Payload::Fragment(Fragment {
+                chunk: Zeroizing::new(chunk.to_vec()),
+                count,
+                id,
+                index: index as u16,
+                kind: payload.kind(),
+            })
+        })
+        .collect())
+}
+
+/// Fragments received so far for a given payload.
+struct Buffer {
+    chunks: Vec<Option<Zeroizing<Vec<u8>>>>,
+    kind: Kind,
+    size: usize,
+    started: Instant,
+}
+
+/// Puts fragmented payloads back together. Incomplete payloads are dropped
+/// after a timeout and the fragments kept in memory are capped.
+#[derive(Default)]
+pub struct Reassembler {
+    buffers: HashMap<u64, Buffer>,
+}
+
+impl Reassembler {
+    /// Stores a fragment, returning the whole payload once every fragment is
+    /// there.
+    pub fn insert(&mut self, fragment: Fragment, now: Instant) -> Result<Option<Payload>, u16> {
+        self.buffers.retain(|_, buffer| {
+            now.duration_since(buffer.started) < Duration::from_secs(FRAGMENT_TIMEOUT)
+        });
+
+        if fragment.count < 2 || fragment.index >= fragment.count || fragment.kind == Kind::Fragment
+        {
+            return Err(408);
+        }
+
+        let size = self.size();
+        let buffer = self.buffers.entry(fragment.id).or_insert_with(|| Buffer {
+            chunks: vec![None; fragment.count as usize],
+            kind: fragment.kind,
+            size: 0,
+            started: now,
+        });
+
+        if buffer.chunks.len() != fragment.count as usize || buffer.kind != fragment.kind {
+            return Err(408);
+        }
+
+        if buffer.chunks[fragment.index as usize].is_some() {
+            return Ok(None);
+        }
+
+        if size + fragment.chunk.len() > MAX_REASSEMBLY_SIZE {
+            self.buffers.remove(&fragment.id);
+
+            return Err(409);
+        }
+
+        buffer.size += fragment.chunk.len();
+        buffer.chunks[fragment.index as usize] = Some(fragment.chunk);
+
+        if buffer.chunks.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let buffer = self.buffers.remove(&fragment.id).unwrap();
+        let bytes = Zeroizing::new(
+            buffer
+                .chunks
+                .iter()
+                .flat_map(|chunk| chunk.as_ref().unwrap().iter().copied())
+                .collect::<Vec<u8>>(),
+        );
+
+        Payload::from_bytes(buffer.kind, &bytes).map(Some)
+    }
+
+    fn size(&self) -> usize { self.buffers.values().map(|buffer| buffer.size).sum() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_fragments(length: usize, id: u64) -> (Payload, Vec<Fragment>) {
+        let payload = Payload::text(&"a".repeat(length - 8), id);
+        let fragments = split(payload.clone())
+            .unwrap()
+            .into_iter()
+            .map(|payload| match payload {
+                Payload::Fragment(fragment) => fragment,
+                _ => panic!("not a fragment"),
+            })
+            .collect();
+
+        (payload, fragments)
+    }
+
+    #[test]
+    fn test_fragment() {
+        let payload = Payload::text("foo", 42);
+
+        // Small payloads should be left alone.
+        assert_eq!(split(payload.clone()), Ok(vec![payload]));
+
+        let (_, fragments) = create_fragments(FRAGMENT_SIZE * 2 + 1, 42);
+
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments[2].chunk.len(), 1);
+
+        // Retransmissions should be fragmented the same way.
+        assert_eq!(create_fragments(FRAGMENT_SIZE * 2 + 1, 42).1, fragments);
+        assert!(fragments.iter().all(|fragment| fragment.id == 42));
+
+        // Payloads too large to be put back together should not be sent.
+        assert_eq!(
+            split(Payload::text(&"a".repeat(MAX_REASSEMBLY_SIZE), 42)),
+            Err(410)
+        );
+
+        for fragment in fragments {
+            assert_eq!(Fragment::from_bytes(&fragment.to_bytes()), Ok(fragment));
+        }
+
+        assert_eq!(Fragment::from_bytes(&[0; 12]), Err(408));
+    }
+
+    #[test]
+    fn test_fragment_out_of_order() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let (payload, mut fragments) = create_fragments(FRAGMENT_SIZE * 3, 42);
+        let first_fragment = fragments.remove(0);
+
+        fragments.reverse();
+
+        for fragment in fragments {
+            assert_eq!(reassembler.insert(fragment, now), Ok(None));
+        }
+
+        // The payload should be delivered once, when complete.
+        assert_eq!(reassembler.insert(first_fragment, now), Ok(Some(payload)));
+        assert!(reassembler.buffers.is_empty());
+    }
+
+    #[test]
+    fn test_fragment_missing() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let (_, mut fragments) = create_fragments(FRAGMENT_SIZE * 3, 42);
+
+        fragments.pop();
+
+        for fragment in fragments {
+            assert_eq!(reassembler.insert(fragment, now), Ok(None));
+        }
+
+        assert_eq!(reassembler.size(), FRAGMENT_SIZE * 2);
+
+        // Incomplete payloads should be dropped after a while.
+        let (_, mut fragments) = create_fragments(FRAGMENT_SIZE * 2, 43);
+        let later = now + Duration::from_secs(FRAGMENT_TIMEOUT);
+
+        assert_eq!(reassembler.insert(fragments.remove(0), later), Ok(None));
+        assert_eq!(reassembler.buffers.len(), 1);
+        assert_eq!(reassembler.size(), FRAGMENT_SIZE);
+    }
+
+    #[test]
+    fn test_fragment_retransmission() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let (payload, mut fragments) = create_fragments(FRAGMENT_SIZE * 3, 42);
+
+        fragments.pop();
+
+        for fragment in fragments {
+            assert_eq!(reassembler.insert(fragment, now), Ok(None));
+        }
+
+        // A retransmission should fill the gaps left by the previous attempt.
+        let (_, fragments) = create_fragments(FRAGMENT_SIZE * 3, 42);
+
+        assert_eq!(reassembler.insert(fragments[0].clone(), now), Ok(None));
+        assert_eq!(
+            reassembler.insert(fragments[2].clone(), now),
+            Ok(Some(payload))
+        );
+        assert!(reassembler.buffers.is_empty());
+    }
+
+    #[test]
+    fn test_fragment_malformed() {
+        let mut reassembler = Reassembler::default();
+        let now = Instant::now();
+        let (_, mut fragments) = create_fragments(FRAGMENT_SIZE * 2, 42);
+        let mut fragment = fragments.remove(0);
+
+        fragment.index = 2;
+
+        assert_eq!(
+            reassembler.insert(Fragment::from_bytes(&fragment.to_bytes()).unwrap(), now),
+            Err(408)
+        );
+
+        fragment.index = 0;
+        fragment.kind = Kind::Fragment;
+
+        assert_eq!(reassembler.insert(fragment, now), Err(408));
+
+        // Fragments should not be able to exhaust the memory.
+        let (_, mut fragments) = create_fragments(MAX_REASSEMBLY_SIZE, 42);
+
+        fragments.pop();
+
+        for fragment in fragments {
+            assert_eq!(reassembler.insert(fragment, now), Ok(None));
+        }
+
+        let (_, mut fragments) = create_fragments(FRAGMENT_SIZE * 2, 43);
+
+        assert_eq!(reassembler.insert(fragments.remove(0), now), Err(409));
+        assert_eq!(reassembler.buffers.len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, net::SocketAddr, str::FromStr, sync::Arc};
+use zeroize::Zeroize;
+
+use crate::{
+    key::Key,
+    network::Network,
+    peers::{identify, Peers},
+};
+
+const MEMBER_KEY_LABEL: &[u8] = b"yume member key";
+
+/// How the key exchange with every remote peer is authenticated, every pair
+/// of peers running its own key exchange either way. Both only prove that a
+/// peer belongs to the group: any member can derive the key of any pair.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GroupKey {
+    /// Derives the key of every pair of peers out of the pre-shared key, which
+    /// binds every key exchange to the addresses of both peers.
+    Pairwise,
+    /// Authenticates every key exchange with the pre-shared key itself, if
+    /// any.
+    Shared,
+}
+
+impl FromStr for GroupKey {
+    type Err = String;
+
+    fn from_str(group_key: &str) -> Result<Self, Self::Err> {
+        match group_key {
+            "pairwise" => Ok(GroupKey::Pairwise),
+            "shared" => Ok(GroupKey::Shared),
+            _ => Err(format!("unknown group key {}", group_key)),
+        }
+    }
+}
+
+/// Derives the key of a pair of peers out of the group key, both peers
+/// feeding their addresses in the same order.
+pub fn derive_member_key(group_key: &Key, local: SocketAddr, remote: SocketAddr) -> Key {
+    let mut addresses = [identify(local), identify(remote)];
+
+    addresses.sort();
+
+    let mut value = Key::derive(
+        &group_key.value,
+        &[],
+        &[
+            MEMBER_KEY_LABEL,
+            addresses[0].as_bytes(),
+            &[0],
+            addresses[1].as_bytes(),
+        ],
+    );
+    let key = Key::new(Some(value));
+
+    value.zeroize();
+
+    key
+}
+
+/// Returns the key authenticating the key exchange with every remote peer:
+/// the key of every pair of peers or the pre-shared key itself.
+pub fn authentication_keys(
+    group_key: GroupKey,
+    pre_shared_key: Option<&Arc<Key>>,
+    network: &Network,
+    peers: &Peers,
+) -> Result<HashMap<SocketAddr, Arc<Key>>, u16> {
+    match (group_key, pre_shared_key) {
+        (GroupKey::Shared, key) => Ok(key
+            .map(|key| {
+                peers
+                    .remotes
+                    .iter()
+                    .map(|remote| (*remote, key.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()),
+        (GroupKey::Pairwise, Some(key)) => peers
+            .remotes
+            .iter()
+            .map(|remote| {
+                let local = network.local_address(*remote)?;
+
+                Ok((*remote, Arc::new(derive_member_key(key, local, *remote))))
+            })
+            .collect(),
+        (GroupKey::Pairwise, None) => Err(110),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_key() {
+        let group_key = Key::new(None);
+        let a = "[2001:3984:3989::10]:3001".parse().unwrap();
+        let b = "[2001:3984:3989::20]:3001".parse().unwrap();
+        let c = "[2001:3984:3989::20]:3002".parse().unwrap();
+
+        // Both peers should derive the very same key, unlike other pairs.
+        assert_eq!(
+            derive_member_key(&group_key, a, b).value[..],
+            derive_member_key(&group_key, b, a).value[..]
+        );
+        assert_ne!(
+            derive_member_key(&group_key, a, b).value[..],
+            derive_member_key(&group_key, a, c).value[..]
+        );
+        assert_ne!(
+            derive_member_key(&group_key, a, b).value[..],
+            group_key.value[..]
+        );
+
+        assert_eq!("shared".parse(), Ok(GroupKey::Shared));
+        assert!("foo".parse::<GroupKey>().is_err());
+    }
+}
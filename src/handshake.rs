@@ -0,0 +1,345 @@
+use async_std::io;
+use ring::{agreement, rand};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Instant};
+use zeroize::Zeroize;
+
+use crate::{
+    error::throw, key::Key, network::Network, peers::Peers, settings::Settings, terminal::println,
+    utils::get_content_from_buffer,
+};
+
+const SESSION_KEY_LABEL: &[u8] = b"yume session key";
+
+/// Ephemeral X25519 public key sent in-band to the remote peer, optionally
+/// signed with a pre-shared key.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Handshake {
+    pub public_key: Vec<u8>,
+    pub signature: Option<String>,
+}
+
+impl Handshake {
+    pub fn new(public_key: Vec<u8>, pre_shared_key: Option<&Key>) -> Self {
+        let signature = pre_shared_key.map(|key| key.encode_handshake_signature(&public_key));
+
+        Handshake {
+            public_key,
+            signature,
+        }
+    }
+
+    /// Checks that the remote peer holds the same pre-shared key, if any.
+    pub fn verify(&self, pre_shared_key: Option<&Key>) -> Result<(), u16> {
+        match pre_shared_key {
+            Some(key) => match &self.signature {
+                Some(signature) => key
+                    .verify_handshake_signature(&self.public_key, signature)
+                    .map_err(|_| 103),
+                None => Err(103),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Derives the session key out of our ephemeral private key and the
+    /// remote peer's handshake.
+    pub fn derive_key(
+        &self,
+        private_key: agreement::EphemeralPrivateKey,
+        public_key: &[u8],
+        pre_shared_key: Option<&Key>,
+    ) -> Result<Key, u16> {
+        // Both peers need to feed the public keys in the same order.
+        let (first, second) = if public_key < self.public_key.as_slice() {
+            (public_key, self.public_key.as_slice())
+        } else {
+            (self.public_key.as_slice(), public_key)
+        };
+        let salt = pre_shared_key.map_or(&[][..], |key| &key.handshake[..]);
+
+        agreement::agree_ephemeral(
+            private_key,
+            &agreement::UnparsedPublicKey::new(&agreement::X25519, &self.public_key),
+            104,
+            |shared_secret| {
+                let mut value =
+                    Key::derive(shared_secret, salt, &[SESSION_KEY_LABEL, first, second]);
+                let key = Key::new(Some(value));
+
+                value.zeroize();
+
+                Ok(key)
+            },
+        )
+    }
+
+    pub fn serialize(&self) -> String { serde_json::to_string(self).unwrap() }
+
+    pub fn deserialize(handshake: String) -> Result<Handshake, ()> {
+        match serde_json::from_str(handshake.as_str()) {
+            Ok(handshake) => Ok(handshake),
+            Err(_) => Err(()),
+        }
+    }
+}
+
+/// Outcome of the key exchange with a remote peer.
+pub struct Exchange {
+    pub key: Key,
+    /// Our serialized handshake, sent again if the remote peer didn't get it.
+    pub handshake: String,
+    /// Ephemeral public keys identifying both peers within the session,
+    /// whatever the addresses they see each other from.
+    pub local_public_key: Vec<u8>,
+    pub remote_public_key: Vec<u8>,
+}
+
+/// Exchanges ephemeral public keys with every remote peer at once and
+/// returns the derived session keys along with our own serialized handshakes.
+/// The key exchange with a remote peer is authenticated by its pre-shared
+/// key, if any. A group goes on with the peers which answered in time.
+pub async fn start(
+    network: &Network,
+    settings: &Settings,
+    peers: &Peers,
+    pre_shared_keys: &HashMap<SocketAddr, Arc<Key>>,
+) -> Result<HashMap<SocketAddr, Exchange>, u16> {
+    let random = rand::SystemRandom::new();
+    let mut pending = HashMap::new();
+    let mut keys: HashMap<SocketAddr, Exchange> = HashMap::new();
+    let mut buffer = vec![0u8; settings.buffer_size];
+
+    // Every remote peer gets its own ephemeral key pair.
+    for remote in &peers.remotes {
+        let private_key = agreement::EphemeralPrivateKey::generate(&agreement::X25519, &random)
+            .map_err(|_| 104_u16)?;
+        let public_key = private_key.compute_public_key().map_err(|_| 104_u16)?;
+        let pre_shared_key = pre_shared_keys.get(remote).map(Arc::as_ref);
+        let handshake = Handshake::new(public_key.as_ref().to_vec(), pre_shared_key).serialize();
+
+        pending.insert(*remote, (private_key, public_key, handshake));
+    }
+
+    println(String::from("Waiting for peer..."), true);
+
+    for _ in 0..settings.handshake_attempts {
+        if pending.is_empty() {
+            break;
+        }
+
+        // Peers might not be listening yet, errors are expected here.
+        for (remote, (_, _, handshake)) in pending.iter() {
+            let _ = network.send_to(handshake.as_bytes(), *remote).await;
+        }
+
+        let deadline = Instant::now() + settings.handshake_interval;
+
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if pending.is_empty() {
+                break;
+            }
+
+            let received =
+                io::timeout(timeout, async { network.recv_from(&mut buffer).await }).await;
+            let (number_of_bytes, origin) = match received {
+                Ok(received) => received,
+                Err(_) => break,
+            };
+
+            let remote_handshake =
+                match Handshake::deserialize(get_content_from_buffer(&buffer, number_of_bytes)) {
+                    Ok(remote_handshake) => remote_handshake,
+                    Err(_) => continue,
+                };
+
+            // Peers done with us might still be waiting for others, and for our
+            // handshake if it got lost.
+            if let Some(exchange) = keys.get(&origin) {
+                if remote_handshake.public_key == exchange.remote_public_key
+                    && network
+                        .send_to(exchange.handshake.as_bytes(), origin)
+                        .await
+                        .is_err()
+                {
+                    throw(202);
+                }
+
+                continue;
+            }
+
+            if pending.contains_key(&origin) {
+                let pre_shared_key = pre_shared_keys.get(&origin).map(Arc::as_ref);
+
+                if let Err(code) = remote_handshake.verify(pre_shared_key) {
+                    throw(code);
+
+                    continue;
+                }
+
+                let (private_key, public_key, handshake) = pending.remove(&origin).unwrap();
+
+                // Make sure the peer gets our public key too.
+                if network.send_to(handshake.as_bytes(), origin).await.is_err() {
+                    throw(202);
+                }
+
+                let key = remote_handshake.derive_key(
+                    private_key,
+                    public_key.as_ref(),
+                    pre_shared_key,
+                )?;
+
+                keys.insert(
+                    origin,
+                    Exchange {
+                        key,
+                        handshake,
+                        local_public_key: public_key.as_ref().to_vec(),
+                        remote_public_key: remote_handshake.public_key,
+                    },
+                );
+            }
+        }
+    }
+
+    if keys.is_empty() {
+        return Err(203);
+    }
+
+    for remote in peers
+        .remotes
+        .iter()
+        .filter(|remote| pending.contains_key(remote))
+    {
+        println(
+            format!(
+                "{} didn't answer, going on without it.",
+                peers.name(*remote)
+            ),
+            true,
+        );
+    }
+
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{net::UdpSocket, task};
+    use std::time::Duration;
+
+    fn generate_key_pair() -> (agreement::EphemeralPrivateKey, Vec<u8>) {
+        let random = rand::SystemRandom::new();
+        let private_key =
+            agreement::EphemeralPrivateKey::generate(&agreement::X25519, &random).unwrap();
+        let public_key = private_key.compute_public_key().unwrap().as_ref().to_vec();
+
+        (private_key, public_key)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let pre_shared_key = Key::new(None);
+        let (private_key_a, public_key_a) = generate_key_pair();
+        let (private_key_b, public_key_b) = generate_key_pair();
+        let handshake_a = Handshake::new(public_key_a.clone(), Some(&pre_shared_key));
+        let handshake_b = Handshake::new(public_key_b.clone(), Some(&pre_shared_key));
+
+        // Both peers should authenticate each other.
+        assert_eq!(handshake_a.verify(Some(&pre_shared_key)), Ok(()));
+        assert_eq!(handshake_b.verify(Some(&pre_shared_key)), Ok(()));
+
+        // Both peers should derive the very same session key.
+        let key_a = handshake_b
+            .derive_key(private_key_a, &public_key_a, Some(&pre_shared_key))
+            .unwrap();
+        let key_b = handshake_a
+            .derive_key(private_key_b, &public_key_b, Some(&pre_shared_key))
+            .unwrap();
+
+        assert_eq!(key_a.value[..], key_b.value[..]);
+        assert_ne!(key_a.value[..], pre_shared_key.value[..]);
+
+        // Serializing and deserializing a handshake should not alter it.
+        assert_eq!(
+            Handshake::deserialize(handshake_a.serialize()).unwrap(),
+            handshake_a
+        );
+    }
+
+    #[test]
+    fn test_handshake_authentication() {
+        let pre_shared_key = Key::new(None);
+        let other_pre_shared_key = Key::new(None);
+        let (_, public_key) = generate_key_pair();
+
+        assert_eq!(
+            Handshake::new(public_key.clone(), Some(&other_pre_shared_key))
+                .verify(Some(&pre_shared_key)),
+            Err(103)
+        );
+        assert_eq!(
+            Handshake::new(public_key.clone(), None).verify(Some(&pre_shared_key)),
+            Err(103)
+        );
+        assert_eq!(
+            Handshake {
+                public_key: public_key.clone(),
+                signature: Some(String::from("not base64")),
+            }
+            .verify(Some(&pre_shared_key)),
+            Err(103)
+        );
+        assert_eq!(Handshake::new(public_key, None).verify(None), Ok(()));
+    }
+
+    #[test]
+    fn test_handshake_without_pre_shared_key() {
+        let (private_key_a, public_key_a) = generate_key_pair();
+        let (private_key_b, public_key_b) = generate_key_pair();
+        let key_a = Handshake::new(public_key_b.clone(), None)
+            .derive_key(private_key_a, &public_key_a, None)
+            .unwrap();
+        let key_b = Handshake::new(public_key_a.clone(), None)
+            .derive_key(private_key_b, &public_key_b, None)
+            .unwrap();
+
+        assert_eq!(key_a.value[..], key_b.value[..]);
+    }
+
+    #[test]
+    fn test_start_without_offline_peer() {
+        task::block_on(async {
+            let settings = Settings {
+                handshake_attempts: 3,
+                handshake_interval: Duration::from_millis(100),
+                ..Settings::new(Default::default()).unwrap()
+            };
+            let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+            let offline = UdpSocket::bind(local).await.unwrap().local_addr().unwrap();
+            let network_a = Network::bind(Some(local), &[], 0).await.unwrap();
+            let network_b = Network::bind(Some(local), &[], 0).await.unwrap();
+            let address_a = network_a.socket.local_addr().unwrap();
+            let address_b = network_b.socket.local_addr().unwrap();
+            let peers_a = Peers::new(vec![address_b, offline]);
+            let peers_b = Peers::new(vec![address_a, offline]);
+            let pre_shared_keys = HashMap::new();
+
+            let (keys_a, keys_b) = futures::join!(
+                start(&network_a, &settings, &peers_a, &pre_shared_keys),
+                start(&network_b, &settings, &peers_b, &pre_shared_keys)
+            );
+            let (keys_a, keys_b) = (keys_a.unwrap(), keys_b.unwrap());
+
+            // The peers which answered should go on without the offline one.
+            assert_eq!(keys_a.keys().collect::<Vec<_>>(), vec![&address_b]);
+            assert_eq!(keys_b.keys().collect::<Vec<_>>(), vec![&address_a]);
+            assert_eq!(
+                keys_a[&address_b].key.value[..],
+                keys_b[&address_a].key.value[..]
+            );
+        });
+    }
+}
@@ -6,10 +6,18 @@ use std::{
 };
 
 lazy_static! {
-    static ref COMMANDS: HashMap<&'static str, &'static str> =
-        vec![("help", "display help"), ("quit", "quit application"),]
-            .into_iter()
-            .collect();
+    static ref COMMANDS: HashMap<&'static str, &'static str> = vec![
+        ("help", "display help"),
+        ("peers", "display the state of every peer"),
+        ("ping", "check that the peer is still reachable"),
+        ("quit", "quit application"),
+        (
+            "verify",
+            "display the session fingerprint to compare with the peer"
+        ),
+    ]
+    .into_iter()
+    .collect();
 }
 
 pub async fn render() {
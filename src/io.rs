@@ -1,19 +1,22 @@
 use crossterm::terminal::size;
+use zeroize::Zeroizing;
 
+/// Message being typed, wiped once replaced.
 #[derive(Clone)]
 pub struct Line {
-    pub content: String,
+    pub content: Zeroizing<String>,
     pub length: u8,
 }
 
 impl Line {
-    pub fn new(content: String) -> Self {
-        let length = Self::get_content_lines_length(content.clone());
-
-        Line { content, length }
+    pub fn new(content: &str) -> Self {
+        Line {
+            content: Zeroizing::new(String::from(content)),
+            length: Self::get_content_lines_length(content),
+        }
     }
 
-    pub fn get_content_lines_length(content: String) -> u8 {
+    pub fn get_content_lines_length(content: &str) -> u8 {
         let content_length = content.len() as f64;
         let terminal_width = size().unwrap().0 as f64;
 
@@ -1,35 +1,68 @@
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::{decode, encode};
 use crossterm::{cursor, execute, style, style::Print, terminal};
-use ring::{digest, hmac};
+use ring::{digest, hkdf, hmac};
 use std::{
     fmt,
     io::{stdout, Write},
+    net::IpAddr,
 };
+use zeroize::Zeroize;
 
-use crate::{message::Message, utils::generate_random_array};
+use crate::{
+    message::Message,
+    peers::{canonicalize, Peers},
+    utils::generate_random_array,
+};
+
+const AUTHENTICATION_KEY_LABEL: &[u8] = b"yume authentication key";
+const ENCRYPTION_KEY_LABEL: &[u8] = b"yume encryption key";
+const ENCRYPTION_KEY_LENGTH: usize = 32;
+const HANDSHAKE_KEY_LABEL: &[u8] = b"yume handshake key";
+const PASSPHRASE_SALT_LABEL: &[u8] = b"yume passphrase salt";
+
+/// Output length of the HKDF expansion, as expected by ring.
+struct Length(usize);
+
+impl hkdf::KeyType for Length {
+    fn len(&self) -> usize { self.0 }
+}
 
-#[derive(Clone)]
+/// Key value along with its subkeys, each primitive getting its own subkey
+/// derived via HKDF. Keys are not meant to be cloned but shared by reference,
+/// their bytes being wiped on drop.
 pub struct Key {
-    pub secret: hmac::Key,
+    pub authentication: hmac::Key,
+    pub encryption: [u8; ENCRYPTION_KEY_LENGTH],
+    pub handshake: [u8; digest::SHA512_OUTPUT_LEN],
     pub value: [u8; digest::SHA512_OUTPUT_LEN],
 }
 
 impl Key {
-    pub fn base64_decode(value: String) -> Result<[u8; digest::SHA512_OUTPUT_LEN], u16> {
-        match decode(&value) {
-            Ok(value) => {
+    pub fn base64_decode(value: &str) -> Result<[u8; digest::SHA512_OUTPUT_LEN], u16> {
+        // Pasted keys might come with surrounding whitespace.
+        match decode(value.trim()) {
+            Ok(mut value) => {
                 let mut key_value = [0; digest::SHA512_OUTPUT_LEN];
-                let to_array = &value[..key_value.len()];
+                let is_valid = value.len() == key_value.len();
+
+                if is_valid {
+                    key_value.copy_from_slice(&value);
+                }
 
-                key_value.copy_from_slice(&value[..to_array.len()]);
+                value.zeroize();
 
-                Ok(key_value)
+                if is_valid {
+                    Ok(key_value)
+                } else {
+                    Err(102)
+                }
             }
             Err(_) => Err(102),
         }
     }
 
-    pub fn base64_encode(&self) -> String { encode(&self.value.to_vec()) }
+    pub fn base64_encode(&self) -> String { encode(self.value) }
 
     pub fn new(value: Option<[u8; digest::SHA512_OUTPUT_LEN]>) -> Self {
         let mut is_new_key = false;
@@ -40,11 +73,21 @@ impl Key {
                 generate_random_array()
             }
         };
-        let key = Key {
-            secret: hmac::Key::new(hmac::HMAC_SHA512, value.as_ref()),
+        let mut authentication = Key::derive(&value, &[], &[AUTHENTICATION_KEY_LABEL]);
+        let mut encryption = Key::derive(&value, &[], &[ENCRYPTION_KEY_LABEL]);
+        let mut key = Key {
+            authentication: hmac::Key::new(hmac::HMAC_SHA512, &authentication),
+            encryption: [0; ENCRYPTION_KEY_LENGTH],
+            handshake: Key::derive(&value, &[], &[HANDSHAKE_KEY_LABEL]),
             value,
         };
 
+        // HKDF outputs of different lengths share the same prefix.
+        key.encryption
+            .copy_from_slice(&encryption[..ENCRYPTION_KEY_LENGTH]);
+        authentication.zeroize();
+        encryption.zeroize();
+
         // Print the newly generated key for reuse.
         if is_new_key {
             execute!(
@@ -52,7 +95,7 @@ impl Key {
                 terminal::Clear(terminal::ClearType::CurrentLine),
                 cursor::MoveToColumn(0),
                 style::SetForegroundColor(style::Color::DarkRed),
-                Print(key.clone()),
+                Print(&key),
                 style::SetForegroundColor(style::Color::Reset),
                 Print("\n"),
                 cursor::MoveToColumn(0),
@@ -63,33 +106,117 @@ impl Key {
         key
     }
 
-    pub fn encode_message_signature(&self, message: Vec<u8>) -> String {
-        encode(
-            &hmac::sign(&self.secret, message.as_slice())
-                .as_ref()
-                .to_vec(),
+    /// Derives a new key value from some input key material via HKDF-SHA512.
+    pub fn derive(secret: &[u8], salt: &[u8], info: &[&[u8]]) -> [u8; digest::SHA512_OUTPUT_LEN] {
+        let mut value = [0; digest::SHA512_OUTPUT_LEN];
+
+        hkdf::Salt::new(hkdf::HKDF_SHA512, salt)
+            .extract(secret)
+            .expand(info, Length(value.len()))
+            .and_then(|okm| okm.fill(&mut value))
+            .unwrap();
+
+        value
+    }
+
+    /// Stretches a passphrase shared by all peers with Argon2id, the salt
+    /// being computed out of all peer addresses.
+    pub fn from_passphrase(
+        passphrase: &str,
+        local_ip: IpAddr,
+        peers: &Peers,
+        params: Params,
+    ) -> Result<Self, u16> {
+        let mut addresses = peers
+            .remotes
+            .iter()
+            .map(|remote| canonicalize(*remote).to_string())
+            .chain(Some(local_ip.to_string()))
+            .collect::<Vec<String>>();
+
+        addresses.sort();
+
+        let mut context = digest::Context::new(&digest::SHA256);
+
+        context.update(PASSPHRASE_SALT_LABEL);
+        for address in addresses.iter() {
+            context.update(&(address.len() as u64).to_be_bytes());
+            context.update(address.as_bytes());
+        }
+
+        Key::stretch(passphrase, context.finish().as_ref(), params)
+    }
+
+    /// Stretches a passphrase into a key with Argon2id.
+    pub fn stretch(passphrase: &str, salt: &[u8], params: Params) -> Result<Self, u16> {
+        let mut value = [0; digest::SHA512_OUTPUT_LEN];
+
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), salt, &mut value)
+            .map_err(|_| 106_u16)?;
+
+        let key = Key::new(Some(value));
+
+        value.zeroize();
+
+        Ok(key)
+    }
+
+    /// Validates the Argon2 parameters used to stretch passphrases.
+    pub fn passphrase_params(
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    ) -> Result<Params, u16> {
+        Params::new(
+            memory,
+            iterations,
+            parallelism,
+            Some(digest::SHA512_OUTPUT_LEN),
         )
+        .map_err(|_| 107)
     }
 
-    pub fn get_half_key_value(&self) -> [u8; 32] {
-        // Resize key value from 64 to 32 to match GenericArray!
-        let mut half_key_value = [0; 32];
-        let to_array = &self.value[..half_key_value.len()];
+    pub fn encode_handshake_signature(&self, public_key: &[u8]) -> String {
+        let handshake_key = hmac::Key::new(hmac::HMAC_SHA512, &self.handshake);
+
+        encode(hmac::sign(&handshake_key, public_key).as_ref())
+    }
 
-        half_key_value.copy_from_slice(&self.value[..to_array.len()]);
+    pub fn verify_handshake_signature(
+        &self,
+        public_key: &[u8],
+        signature: &str,
+    ) -> Result<(), String> {
+        let handshake_key = hmac::Key::new(hmac::HMAC_SHA512, &self.handshake);
 
-        half_key_value
+        decode(signature)
+            .ok()
+            .and_then(|signature| hmac::verify(&handshake_key, public_key, &signature).ok())
+            .ok_or_else(|| String::from("Invalid handshake signature!"))
+    }
+
+    pub fn sign_message(&self, message: &[u8]) -> Vec<u8> {
+        hmac::sign(&self.authentication, message).as_ref().to_vec()
     }
 
     pub fn verify_message_signature(&self, message: &Message) -> Result<(), String> {
-        let content = message.content.clone();
-        let signature = self.encode_message_signature(content);
+        hmac::verify(
+            &self.authentication,
+            &message.signed_content(),
+            &message.signature,
+        )
+        .map_err(|_| String::from("Invalid message signature!"))
+    }
+}
 
-        if signature.as_str() == message.signature {
-            Ok(())
-        } else {
-            Err(String::from("Invalid message signature!"))
-        }
+/// The HMAC key lives within ring and can't be wiped from here, every other
+/// byte is.
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.encryption.zeroize();
+        self.handshake.zeroize();
+        self.value.zeroize();
     }
 }
 
@@ -102,6 +229,7 @@ impl fmt::Display for Key {
 #[cfg(test)]
 mod utils {
     use super::*;
+    use std::net::SocketAddr;
 
     #[test]
     fn test_key() {
@@ -113,12 +241,92 @@ mod utils {
             (4.0 * (digest::SHA512_OUTPUT_LEN as f64 / 3.0).ceil()) as usize
         );
 
-        let decoded_key = Key::base64_decode(encoded_key).unwrap();
-        let mut half_decoded_key = [0; 32];
-        let to_array = &decoded_key[..half_decoded_key.len()];
+        let decoded_key = Key::base64_decode(&encoded_key).unwrap();
+
+        assert_eq!(decoded_key[..], key.value[..]);
+        assert_eq!(Key::base64_decode("Zm9v"), Err(102));
+        assert_eq!(Key::new(Some(decoded_key)).encryption, key.encryption);
+    }
+
+    #[test]
+    fn test_key_subkeys() {
+        let mut value = [0; digest::SHA512_OUTPUT_LEN];
+
+        for (index, byte) in value.iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+
+        let key = Key::new(Some(value));
+
+        // Known-answer vectors for HKDF-SHA512 with an empty salt.
+        assert_eq!(
+            key.encryption[..],
+            decode("+9U4c54tPWMy+RbwzQ0ffAQVuH+xGX7CavMY/4w6Wtk=").unwrap()[..]
+        );
+        assert_eq!(
+            key.handshake[..],
+            decode(
+                "TaJgVf5yjz6bzEb2YrLzTOaMBv0tduvN/\
+                 sOaX25JF6QwnqWETvP3eh8f3S47xZoy1VcPXYLloaETMrrJrEBB7A=="
+            )
+            .unwrap()[..]
+        );
+        assert_eq!(
+            key.sign_message(b"yume"),
+            decode(
+                "VMphyiIbh2kRZ2gUWI6navJdsc46FpnnQmqjyS/Ju8jeK2Iet50VhnYqMf6py5zh26VAeHCa/\
+                 +iphtrSCdYcJw=="
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            key.encode_handshake_signature(b"yume"),
+            "EamIw1zAUkvoMPdPs47gRKwxm2B0xbh9QNB2EmRrbC/\
+             8vzq5Eqf5zeH0kIogz5dNuoZ53MRtWYQvQ3xPThcycA=="
+        );
+
+        // Every subkey should differ from the others and from the key value.
+        assert_ne!(key.encryption[..], key.value[..ENCRYPTION_KEY_LENGTH]);
+        assert_ne!(key.handshake[..], key.value[..]);
+        assert_ne!(
+            encode(key.sign_message(b"yume")),
+            key.encode_handshake_signature(b"yume")
+        );
+    }
+
+    #[test]
+    fn test_key_from_passphrase() {
+        let local_peer: SocketAddr = "[2001:3984:3989::10]:3001".parse().unwrap();
+        let remote_peer: SocketAddr = "[2001:3984:3989::20]:3001".parse().unwrap();
+        let params = || Key::passphrase_params(256, 1, 1).unwrap();
+        let key_a = Key::from_passphrase(
+            "correct horse battery staple",
+            local_peer.ip(),
+            &Peers::new(vec![remote_peer]),
+            params(),
+        )
+        .unwrap();
+        let key_b = Key::from_passphrase(
+            "correct horse battery staple",
+            remote_peer.ip(),
+            &Peers::new(vec![local_peer]),
+            params(),
+        )
+        .unwrap();
+        let key_c = Key::from_passphrase(
+            "correct horse battery",
+            local_peer.ip(),
+            &Peers::new(vec![remote_peer]),
+            params(),
+        )
+        .unwrap();
 
-        half_decoded_key.copy_from_slice(&decoded_key[..to_array.len()]);
+        // Both peers should derive the very same key.
+        assert_eq!(key_a.value[..], key_b.value[..]);
+        // Any other passphrase should lead to another key.
+        assert_ne!(key_a.value[..], key_c.value[..]);
 
-        assert_eq!(half_decoded_key, key.get_half_key_value());
+        // Invalid parameters should be rejected.
+        assert_eq!(Key::passphrase_params(0, 0, 0).err(), Some(107));
     }
 }
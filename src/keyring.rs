@@ -0,0 +1,298 @@
+use aead::{generic_array::GenericArray, Aead, NewAead, Payload};
+use argon2::Params;
+use base64::{decode, encode};
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    config::{KEYRING_PATH, NONCE_LENGTH},
+    key::Key,
+    terminal::{println, prompt_secret},
+    utils::{generate_random_array, get_home_path},
+};
+
+const SALT_LENGTH: usize = 16;
+
+#[derive(StructOpt, Debug)]
+pub enum KeyringCommand {
+    #[structopt(about = "add a contact key, a new one being generated if none is entered")]
+    Add { name: String },
+    #[structopt(about = "delete a contact key")]
+    Delete { name: String },
+    #[structopt(about = "list the contacts")]
+    List,
+    #[structopt(about = "rename a contact")]
+    Rename { name: String, new_name: String },
+}
+
+/// Keyring as stored on disk, encrypted under a key stretched from the
+/// master passphrase.
+#[derive(Deserialize, Serialize)]
+struct SealedKeyring {
+    content: String,
+    iterations: u32,
+    memory: u32,
+    nonce: String,
+    parallelism: u32,
+    salt: String,
+}
+
+/// Pre-shared keys indexed by contact name.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Keyring {
+    entries: BTreeMap<String, String>,
+}
+
+impl Keyring {
+    pub fn add(&mut self, name: String, key: &Key) -> Result<(), u16> {
+        if self.entries.contains_key(&name) {
+            return Err(505);
+        }
+
+        self.entries.insert(name, key.base64_encode());
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<(), u16> {
+        self.entries.remove(name).map(|_| ()).ok_or(504)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Key, u16> {
+        let value = self.entries.get(name).ok_or(504_u16)?;
+
+        Ok(Key::new(Some(Key::base64_decode(value)?)))
+    }
+
+    pub fn list(&self) -> Vec<&String> { self.entries.keys().collect() }
+
+    pub fn rename(&mut self, name: &str, new_name: String) -> Result<(), u16> {
+        if self.entries.contains_key(&new_name) {
+            return Err(505);
+        }
+
+        let value = self.entries.remove(name).ok_or(504_u16)?;
+
+        self.entries.insert(new_name, value);
+
+        Ok(())
+    }
+
+    /// Reads and decrypts the keyring, a missing file being an empty keyring.
+    pub fn load(path: &Path, passphrase: &str) -> Result<Self, u16> {
+        if !path.exists() {
+            return Ok(Keyring::default());
+        }
+
+        let sealed_keyring: SealedKeyring = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .ok_or(501_u16)?;
+        let salt = decode(&sealed_keyring.salt).map_err(|_| 501_u16)?;
+        let nonce = decode(&sealed_keyring.nonce).map_err(|_| 501_u16)?;
+        let content = decode(&sealed_keyring.content).map_err(|_| 501_u16)?;
+
+        if nonce.len() != NONCE_LENGTH {
+            return Err(501);
+        }
+
+        let params = Key::passphrase_params(
+            sealed_keyring.memory,
+            sealed_keyring.iterations,
+            sealed_keyring.parallelism,
+        )?;
+        let key = Key::stretch(passphrase, &salt, params)?;
+        let plaintext = create_aead(&key)
+            .decrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &content,
+                    aad: &salt,
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| 503_u16)?;
+
+        serde_json::from_slice(&plaintext).map_err(|_| 501)
+    }
+
+    /// Encrypts and writes the keyring, with a fresh salt and nonce every
+    /// time.
+    pub fn save(&self, path: &Path, passphrase: &str, params: Params) -> Result<(), u16> {
+        let random_array = generate_random_array();
+        let (salt, nonce) = random_array.split_at(SALT_LENGTH);
+        let nonce = &nonce[..NONCE_LENGTH];
+        let (memory, iterations, parallelism) = (params.m_cost(), params.t_cost(), params.p_cost());
+        let key = Key::stretch(passphrase, salt, params)?;
+        let plaintext = Zeroizing::new(serde_json::to_vec(self).unwrap());
+        let content = create_aead(&key)
+            .encrypt(
+                GenericArray::from_slice(nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: salt,
+                },
+            )
+            .map_err(|_| 502_u16)?;
+        let sealed_keyring = SealedKeyring {
+            content: encode(content),
+            iterations,
+            memory,
+            nonce: encode(nonce),
+            parallelism,
+            salt: encode(salt),
+        };
+
+        write(path, &serde_json::to_string(&sealed_keyring).unwrap()).map_err(|_| 502)
+    }
+}
+
+/// Contact keys are wiped along with the keyring.
+impl Drop for Keyring {
+    fn drop(&mut self) {
+        for value in self.entries.values_mut() {
+            value.zeroize();
+        }
+    }
+}
+
+fn create_aead(key: &Key) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(GenericArray::clone_from_slice(&key.encryption))
+}
+
+/// Writes the keyring file, only readable by its owner.
+fn write(path: &Path, content: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    options.create(true).write(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+/// Returns the keyring location within the home directory.
+pub fn default_path() -> PathBuf { get_home_path(KEYRING_PATH) }
+
+/// Runs a keyring command, the master passphrase being prompted first.
+pub fn manage(command: KeyringCommand, path: &Path, params: Params) -> Result<(), u16> {
+    let is_new_keyring = !path.exists();
+    let passphrase = prompt_secret(Some(String::from("Enter the keyring master passphrase:")))
+        .map_err(|_| 301_u16)?;
+
+    if is_new_keyring {
+        let confirmation =
+            prompt_secret(Some(String::from("Confirm the keyring master passphrase:")))
+                .map_err(|_| 301_u16)?;
+
+        if confirmation != passphrase {
+            return Err(506);
+        }
+    }
+
+    let mut keyring = Keyring::load(path, &passphrase)?;
+
+    match command {
+        KeyringCommand::Add { name } => {
+            let secret_key = prompt_secret(Some(String::from(
+                "Enter the contact key or press enter to generate a new one:",
+            )))
+            .map_err(|_| 301_u16)?;
+            let key = if secret_key.is_empty() {
+                Key::new(None)
+            } else {
+                Key::new(Some(Key::base64_decode(&secret_key)?))
+            };
+
+            keyring.add(name, &key)?;
+        }
+        KeyringCommand::Delete { name } => keyring.delete(&name)?,
+        KeyringCommand::List => {
+            for name in keyring.list() {
+                println(name.clone(), false);
+            }
+
+            return Ok(());
+        }
+        KeyringCommand::Rename { name, new_name } => keyring.rename(&name, new_name)?,
+    }
+
+    keyring.save(path, &passphrase, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn create_path(name: &str) -> PathBuf {
+        let suffix = generate_random_array()[..6]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        env::temp_dir().join(format!("yume-{}", suffix)).join(name)
+    }
+
+    fn create_params() -> Params { Key::passphrase_params(256, 1, 1).unwrap() }
+
+    #[test]
+    fn test_keyring() {
+        let mut keyring = Keyring::default();
+        let key = Key::new(None);
+
+        assert_eq!(keyring.add(String::from("alice"), &key), Ok(()));
+        assert_eq!(keyring.add(String::from("alice"), &key), Err(505));
+        assert_eq!(keyring.get("alice").unwrap().value[..], key.value[..]);
+        assert_eq!(keyring.get("bob").err(), Some(504));
+
+        assert_eq!(keyring.add(String::from("bob"), &Key::new(None)), Ok(()));
+        assert_eq!(keyring.rename("alice", String::from("bob")), Err(505));
+        assert_eq!(keyring.rename("carol", String::from("dave")), Err(504));
+        assert_eq!(keyring.rename("alice", String::from("carol")), Ok(()));
+        assert_eq!(keyring.get("carol").unwrap().value[..], key.value[..]);
+        assert_eq!(keyring.list(), vec!["bob", "carol"]);
+
+        assert_eq!(keyring.delete("bob"), Ok(()));
+        assert_eq!(keyring.delete("bob"), Err(504));
+        assert_eq!(keyring.list(), vec!["carol"]);
+    }
+
+    #[test]
+    fn test_keyring_at_rest() {
+        let path = create_path("keyring");
+        let mut keyring = Keyring::default();
+        let key = Key::new(None);
+
+        // A missing keyring should be empty.
+        assert_eq!(Keyring::load(&path, "foo"), Ok(Keyring::default()));
+
+        keyring.add(String::from("alice"), &key).unwrap();
+
+        assert_eq!(keyring.save(&path, "foo", create_params()), Ok(()));
+
+        // Keys should not be stored in clear.
+        let content = fs::read_to_string(&path).unwrap();
+
+        assert!(!content.contains("alice"));
+        assert!(!content.contains(&key.base64_encode()));
+
+        assert_eq!(Keyring::load(&path, "foo"), Ok(keyring));
+        assert_eq!(Keyring::load(&path, "bar"), Err(503));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,5 @@
+//! Parts of yume shared with the rendezvous server binary.
+
+pub mod relay;
+pub mod rendezvous;
+pub mod socket;
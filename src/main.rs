@@ -1,90 +1,614 @@
 mod client;
 mod config;
+mod discovery;
+mod envelope;
 mod error;
+mod fingerprint;
+mod fragment;
+mod group;
+mod handshake;
 mod help;
 mod io;
 mod key;
+mod keyring;
 mod message;
+mod network;
+mod padding;
 mod peers;
+mod presence;
+mod punch;
+mod replay;
 mod server;
+mod session;
+mod settings;
 mod terminal;
 mod types;
 mod utils;
+mod wire;
 
-use crate::client::start as start_client;
-use crate::config::{DESCRIPTION, VERSION};
-use crate::error::throw;
+use crate::client::{keep_alive, start as start_client};
+use crate::config::{
+    ARGON2_ITERATIONS, ARGON2_MEMORY, ARGON2_PARALLELISM, CONFIG_PATH, DEFAULT_NICKNAME,
+    DESCRIPTION, VERSION,
+};
+use crate::discovery::{select_peers, Announcement};
+use crate::error::{throw, warn, Error};
+use crate::fingerprint::Fingerprint;
+use crate::group::{authentication_keys, GroupKey};
+use crate::handshake::start as start_handshake;
 use crate::key::Key;
-use crate::peers::Peers;
+use crate::keyring::{default_path, manage as manage_keyring, Keyring, KeyringCommand};
+use crate::network::Network;
+use crate::padding::Padding;
+use crate::peers::{get_zone_index, resolve, Peers};
+use crate::presence::Presence;
+use crate::punch::rendezvous;
 use crate::server::start as start_server;
-use crate::terminal::{enter_secondary_screen, println, prompt};
-use crate::types::SenderReceiver;
+use crate::session::Session;
+use crate::settings::{PartialSettings, Settings};
+use crate::terminal::{
+    confirm, enter_raw_mode, enter_secondary_screen, leave_raw_mode, println, prompt_secret,
+};
+use crate::types::{SenderReceiver, SharedNetwork, SharedPresence, SharedSessions};
+use crate::utils::get_home_path;
 
-use async_std::sync::channel;
+use async_std::sync::{channel, Mutex};
 use async_std::task;
-use std::sync::Arc;
-use structopt::StructOpt;
+use std::{
+    collections::HashMap,
+    env,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use structopt::{clap::AppSettings, StructOpt};
+use yume::relay::{serve as serve_relay, RELAY_PORT};
+use yume::rendezvous::{REGISTRATION_TIMEOUT, RENDEZVOUS_PORT};
+use yume::socket;
 
 #[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(about = "manage the contact keys stored in the keyring")]
+    Keyring(KeyringCommand),
+    #[structopt(about = "forward encrypted envelopes between peers which can't reach each other")]
+    Relay {
+        #[structopt(long, help = "address to listen on, [::]:3004 by default")]
+        address: Option<SocketAddr>,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(setting = AppSettings::SubcommandsNegateReqs)]
 struct Opt {
+    #[structopt(long, help = "Argon2 iterations used to stretch the passphrase")]
+    argon2_iterations: Option<u32>,
+    #[structopt(
+        long,
+        help = "Argon2 memory size in KiB used to stretch the passphrase"
+    )]
+    argon2_memory: Option<u32>,
+    #[structopt(long, help = "Argon2 parallelism used to stretch the passphrase")]
+    argon2_parallelism: Option<u32>,
+    #[structopt(long, help = "size in bytes of the buffer receiving the datagrams")]
+    buffer_size: Option<usize>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "path to the JSON config file, ~/.yume/config.json by default"
+    )]
+    config: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "display the pre-shared key fingerprint and ask for confirmation"
+    )]
+    confirm_key: bool,
+    #[structopt(
+        long,
+        help = "announce ourselves on the local link and pick the peers among the discovered ones"
+    )]
+    discover: bool,
+    #[structopt(
+        long,
+        help = "name or index of the network interface used for the discovery"
+    )]
+    discovery_interface: Option<String>,
+    #[structopt(long, help = "chat with all the given peers, none of them being local")]
+    group: bool,
+    #[structopt(
+        long,
+        default_value = "shared",
+        possible_values = &["pairwise", "shared"],
+        help = "pre-shared key authenticating every key exchange, or a key derived from it per pair"
+    )]
+    group_key: GroupKey,
+    #[structopt(long, help = "also accept messages encoded as JSON")]
+    json_compatibility: bool,
+    #[structopt(long, help = "load the pre-shared key of a contact from the keyring")]
+    contact: Option<String>,
+    #[structopt(long, help = "maximum number of attempts to reach the peer for the key exchange")]
+    handshake_attempts: Option<u64>,
+    #[structopt(long, help = "seconds between two attempts of the key exchange")]
+    handshake_interval: Option<u64>,
+    #[structopt(long, help = "seconds between two heartbeats keeping the sessions alive")]
+    heartbeat_interval: Option<u64>,
+    #[structopt(long, parse(from_os_str), help = "path to the keyring file")]
+    keyring: Option<PathBuf>,
+    #[structopt(
+        long,
+        default_value = "buckets",
+        possible_values = &["buckets", "none", "random"],
+        help = "padding hiding the length of the messages"
+    )]
+    padding: Padding,
+    #[structopt(long, help = "maximum number of attempts to deliver a message")]
+    max_attempts: Option<u32>,
+    #[structopt(long, help = "nickname announced in discovery mode, the user name by default")]
+    nickname: Option<String>,
+    #[structopt(long, help = "derive the pre-shared key from a shared passphrase")]
+    passphrase: bool,
+    #[structopt(long, help = "port of both peers unless given as [address]:port")]
+    port: Option<u16>,
+    #[structopt(
+        long,
+        conflicts_with_all = &["discover", "group"],
+        requires = "room",
+        help = "rendezvous server introducing peers behind NATs, the given peer being a fallback"
+    )]
+    rendezvous: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with = "group",
+        requires = "room",
+        help = "relay to go through if the peer can't be reached directly"
+    )]
+    relay: Option<String>,
+    #[structopt(long, help = "seconds to wait for an ack before the first retransmission")]
+    retransmission_timeout: Option<u64>,
     #[structopt(
-        help = "local and remote IPv6 peer addresses",
-        min_values = 2,
-        required = true
+        help = "optional local and remote peer addresses or hostnames, optionally with a port",
+        min_values = 1,
+        required_unless_one = &["discover", "rendezvous"]
     )]
     peers: Vec<String>,
+    #[structopt(long, help = "name of the room to meet the peer in at the rendezvous server")]
+    room: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
 #[async_std::main]
 async fn main() -> std::io::Result<()> {
-    let peers_from_args = Opt::from_args().peers;
-    let current_peers = Peers::new(peers_from_args[0].clone(), peers_from_args[1].clone());
+    let opt = Opt::from_args();
+    let keyring_path = opt.keyring.unwrap_or_else(default_path);
+    let params = match Key::passphrase_params(
+        opt.argon2_memory.unwrap_or(ARGON2_MEMORY),
+        opt.argon2_iterations.unwrap_or(ARGON2_ITERATIONS),
+        opt.argon2_parallelism.unwrap_or(ARGON2_PARALLELISM),
+    ) {
+        Ok(params) => params,
+        Err(code) => {
+            throw(code);
 
-    let peers = Arc::new(current_peers);
-    let cloned_peers = peers.clone();
+            return Ok(());
+        }
+    };
+
+    match opt.command {
+        Some(Command::Keyring(command)) => {
+            enter_raw_mode();
+
+            let result = manage_keyring(command, &keyring_path, params);
+
+            leave_raw_mode();
+
+            if let Err(code) = result {
+                throw(code);
+            }
+
+            return Ok(());
+        }
+        Some(Command::Relay { address }) => {
+            // The unspecified IPv6 address makes for a dual-stack socket.
+            let address = address.unwrap_or_else(|| {
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), RELAY_PORT)
+            });
+            let socket = match socket::bind(address) {
+                Ok(socket) => socket,
+                Err(_) => {
+                    throw(205);
+
+                    return Ok(());
+                }
+            };
+
+            println!("Relaying on {}", socket.local_addr()?);
+
+            return serve_relay(socket, Duration::from_secs(REGISTRATION_TIMEOUT)).await;
+        }
+        None => (),
+    }
+
+    // Settings given on the command line take precedence over the config file.
+    let settings_from_args = PartialSettings {
+        buffer_size: opt.buffer_size,
+        handshake_attempts: opt.handshake_attempts,
+        handshake_interval: opt.handshake_interval,
+        heartbeat_interval: opt.heartbeat_interval,
+        max_attempts: opt.max_attempts,
+        port: opt.port,
+        retransmission_timeout: opt.retransmission_timeout,
+    };
+    let peers_from_args = opt.peers;
+    let settings = match PartialSettings::load(
+        &opt.config.clone().unwrap_or_else(|| get_home_path(CONFIG_PATH)),
+        opt.config.is_some(),
+    )
+    .map(|settings| settings.merge(settings_from_args))
+    .and_then(Settings::new)
+    {
+        Ok(settings) => settings,
+        Err(code) => {
+            throw(code);
+
+            return Ok(());
+        }
+    };
+
+    // The very same socket is used for the key exchange and the conversation,
+    // bound to the unspecified address if no local address is given.
+    let (local, remotes) = match peers_from_args.as_slice() {
+        [] => (None, &[][..]),
+        [local] if opt.discover => (Some(local), &[][..]),
+        addresses if opt.group && !opt.discover => (None, addresses),
+        [local, remote] if !opt.discover => (Some(local), std::slice::from_ref(remote)),
+        [remote] => (None, std::slice::from_ref(remote)),
+        _ => {
+            throw(610);
+
+            return Ok(());
+        }
+    };
+    let local = match local {
+        Some(local) => match resolve(local, settings.port, None).await {
+            Ok(local) => Some(local),
+            Err(code) => {
+                throw(code);
+
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    let discovery_interface = match opt
+        .discovery_interface
+        .as_deref()
+        .map(|interface| get_zone_index(interface).map_err(|_| 612))
+    {
+        Some(Ok(interface)) => interface,
+        Some(Err(code)) => {
+            throw(code);
+
+            return Ok(());
+        }
+        None => 0,
+    };
 
     enter_secondary_screen();
 
     println(String::from(DESCRIPTION), true);
     println(format!("Version {}\n", VERSION), true);
 
-    let secret_key = prompt(Some(String::from(
-        "Enter secret key or press enter to generate a new one:",
-    )));
+    // The passphrase is only stretched once the peer addresses are known.
+    let mut passphrase = None;
+    let mut pre_shared_key = if let Some(contact) = opt.contact {
+        let key = prompt_secret(Some(String::from("Enter the keyring master passphrase:")))
+            .map_err(|_| 301)
+            .and_then(|passphrase| Keyring::load(&keyring_path, &passphrase))
+            .and_then(|keyring| keyring.get(&contact));
 
-    let key = match secret_key {
-        Ok(secret_key) => Key::new(if secret_key.is_empty() {
-            None
-        } else {
-            match Key::base64_decode(secret_key) {
-                Ok(secret_key) => Some(secret_key),
-                Err(code) => {
-                    throw(code);
+        match key {
+            Ok(key) => Some(Arc::new(key)),
+            Err(code) => {
+                throw(code);
 
-                    return Ok(());
+                return Ok(());
+            }
+        }
+    } else if opt.passphrase {
+        match prompt_secret(Some(String::from(
+            "Enter the passphrase shared with the peers:",
+        ))) {
+            Ok(secret) => {
+                passphrase = Some(secret);
+
+                None
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+
+                return Ok(());
+            }
+        }
+    } else {
+        let secret_key = prompt_secret(Some(String::from(
+            "Enter pre-shared key to authenticate the peers or press enter to skip:",
+        )));
+
+        match secret_key {
+            Ok(secret_key) => {
+                if secret_key.is_empty() {
+                    None
+                } else {
+                    match Key::base64_decode(&secret_key) {
+                        Ok(secret_key) => Some(Arc::new(Key::new(Some(secret_key)))),
+                        Err(code) => {
+                            throw(code);
+
+                            return Ok(());
+                        }
+                    }
                 }
             }
-        }),
-        Err(error) => {
-            eprintln!("{}", error);
+            Err(error) => {
+                eprintln!("{}", error);
+
+                return Ok(());
+            }
+        }
+    };
+
+    // Peers on the same link can find each other instead of exchanging their
+    // addresses beforehand.
+    let remotes = if opt.discover {
+        let announcement = Announcement::new(
+            &opt.nickname
+                .clone()
+                .or_else(|| env::var("USER").ok())
+                .unwrap_or_else(|| String::from(DEFAULT_NICKNAME)),
+            pre_shared_key.as_ref().map(|key| Fingerprint::new(key).to_hex()),
+            local.map_or(settings.port, |local| local.port()),
+        );
+
+        match select_peers(announcement, discovery_interface, opt.group).await {
+            Ok(remotes) => remotes,
+            Err(code) => {
+                throw(code);
+
+                return Ok(());
+            }
+        }
+    } else {
+        remotes.to_vec()
+    };
+
+    let (server, relay, room) = (opt.rendezvous, opt.relay, opt.room);
+    let network_and_peers = async {
+        let peers = match remotes.as_slice() {
+            [] => None,
+            remotes => Some(Peers::resolve(remotes, settings.port, local).await?),
+        };
+        let server = match &server {
+            Some(server) => Some(resolve(server, RENDEZVOUS_PORT, local).await?),
+            None => None,
+        };
+        let relay = match &relay {
+            Some(relay) => Some(resolve(relay, RELAY_PORT, local).await?),
+            None => None,
+        };
+        // The socket has to reach the peers, the rendezvous server and the relay.
+        let references = peers
+            .iter()
+            .flat_map(|peers| peers.remotes.iter().copied())
+            .chain(server)
+            .chain(relay)
+            .collect::<Vec<_>>();
+
+        if references.is_empty() {
+            return Err(603);
+        }
+
+        let mut network = Network::bind(local, &references, settings.port).await?;
+
+        let peers = match (server, room.as_deref()) {
+            (Some(server), Some(room)) => match rendezvous(&network, &settings, server, room).await
+            {
+                Ok((address, remote)) => {
+                    network.set_public_address(remote, address);
+
+                    Peers::new(vec![remote])
+                }
+                // Fall back to a direct connection if the peer address is known.
+                Err(code) => match peers {
+                    Some(peers) => {
+                        warn(Error::from(code));
+
+                        peers
+                    }
+                    None => return Err(code),
+                },
+            },
+            _ => peers.ok_or(603_u16)?,
+        };
+
+        Ok::<_, u16>((network, peers, relay))
+    };
+    let (mut network, mut peers, relay) = match network_and_peers.await {
+        Ok(network_and_peers) => network_and_peers,
+        Err(code) => {
+            throw(code);
 
             return Ok(());
         }
     };
 
+    let local_ip = match network.local_address(peers.remotes[0]) {
+        Ok(address) => address.ip(),
+        Err(code) => {
+            throw(code);
+
+            return Ok(());
+        }
+    };
+
+    if let Ok(address) = network.socket.local_addr() {
+        println(
+            format!("Listening on {}, seen as {}\n", address, local_ip),
+            true,
+        );
+    }
+
+    if let Some(passphrase) = passphrase {
+        match Key::from_passphrase(&passphrase, local_ip, &peers, params) {
+            Ok(key) => pre_shared_key = Some(Arc::new(key)),
+            Err(code) => {
+                throw(code);
+
+                return Ok(());
+            }
+        }
+    }
+
+    // Let the user make sure the right pre-shared key was entered.
+    if let (true, Some(key)) = (opt.confirm_key, &pre_shared_key) {
+        let fingerprint = Fingerprint::new(key);
+
+        println(format!("Fingerprint: {}", fingerprint.to_hex()), true);
+        println(format!("Words: {}", fingerprint.to_words()), false);
+
+        match confirm(String::from("Does the pre-shared key fingerprint match?")) {
+            Ok(true) => (),
+            Ok(false) => {
+                throw(109);
+
+                return Ok(());
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+
+                return Ok(());
+            }
+        }
+    }
+
+    // Every pair of peers runs its own key exchange, whatever the group key.
+    let group_key = opt.group_key;
+    let keys = match authentication_keys(group_key, pre_shared_key.as_ref(), &network, &peers) {
+        Ok(pre_shared_keys) => start_handshake(&network, &settings, &peers, &pre_shared_keys).await,
+        Err(code) => Err(code),
+    };
+
+    // Peers which can't reach each other directly meet at the relay, the key
+    // exchange going through it as well.
+    let keys = match (keys, relay, room.as_deref()) {
+        (Err(203), Some(relay), Some(room)) => {
+            warn(Error::from(203));
+
+            match rendezvous(&network, &settings, relay, room).await {
+                Ok((address, remote)) => {
+                    network.set_public_address(remote, address);
+                    network.set_relay(relay);
+                    peers = Peers::new(vec![remote]);
+
+                    println(format!("Relaying through {}", relay), true);
+
+                    match authentication_keys(group_key, pre_shared_key.as_ref(), &network, &peers)
+                    {
+                        Ok(pre_shared_keys) => {
+                            start_handshake(&network, &settings, &peers, &pre_shared_keys).await
+                        }
+                        Err(code) => Err(code),
+                    }
+                }
+                Err(code) => Err(code),
+            }
+        }
+        (keys, _, _) => keys,
+    };
+
+    // Only the chain keys derived from the session keys are kept.
+    let padding = opt.padding;
+    let (sessions, handshakes): (HashMap<_, _>, HashMap<_, _>) = match keys {
+        Ok(keys) => keys
+            .into_iter()
+            .map(|(remote, exchange)| {
+                let session = Session::new(
+                    &exchange.key,
+                    &exchange.local_public_key,
+                    &exchange.remote_public_key,
+                    padding,
+                );
+
+                ((remote, session), (remote, exchange.handshake))
+            })
+            .unzip(),
+        Err(code) => {
+            throw(code);
+
+            return Ok(());
+        }
+    };
+
+    // The conversation goes on with the peers which answered.
+    let peers = Peers::new(
+        peers.remotes.iter().filter(|remote| sessions.contains_key(remote)).copied().collect(),
+    );
+
     println(String::from("\nYou can start typing!\n"), true);
 
-    let key = Arc::new(key);
-    let cloned_key = key.clone();
+    let network: SharedNetwork = Arc::new(network);
+    let cloned_network = network.clone();
+    let settings = Arc::new(settings);
+    let cloned_settings = settings.clone();
+    let peers = Arc::new(peers);
+    let cloned_peers = peers.clone();
+
+    let sessions: SharedSessions = Arc::new(Mutex::new(sessions));
+    let cloned_sessions = sessions.clone();
+    let handshakes = Arc::new(handshakes);
+    let json_compatibility = opt.json_compatibility;
+    let presence: SharedPresence = Arc::new(Mutex::new(Presence::new(
+        &peers.remotes,
+        settings.heartbeat_interval,
+        Instant::now(),
+    )));
+    let cloned_presence = presence.clone();
 
     let sender_receiver: SenderReceiver = Arc::new(channel(1));
     let cloned_sender_receiver = sender_receiver.clone();
 
+    task::spawn(keep_alive(
+        network.clone(),
+        settings.clone(),
+        peers.clone(),
+        sessions.clone(),
+        presence.clone(),
+        sender_receiver.clone(),
+    ));
+
     task::spawn(async move {
-        start_server(cloned_peers, cloned_key, sender_receiver).await;
+        start_server(
+            cloned_network,
+            cloned_settings,
+            cloned_peers,
+            cloned_sessions,
+            cloned_presence,
+            handshakes,
+            sender_receiver,
+            json_compatibility,
+        )
+        .await;
     });
 
-    start_client(peers.clone(), key, cloned_sender_receiver).await;
+    start_client(
+        network,
+        settings,
+        peers.clone(),
+        sessions,
+        presence,
+        cloned_sender_receiver,
+    )
+    .await;
 
     Ok(())
 }
@@ -1,55 +1,226 @@
 use aead::{generic_array::GenericArray, Aead, NewAead};
 use chacha20poly1305::ChaCha20Poly1305;
 use serde::{Deserialize, Serialize};
-use std::{str, sync::Arc};
+use zeroize::Zeroizing;
 
-use crate::{config::NONCE_LENGTH, key::Key, utils::generate_random_array};
+use crate::{
+    config::{NONCE_LENGTH, PROTOCOL_VERSION},
+    error::Error,
+    key::Key,
+    padding::{unpad, Padding},
+    utils::{generate_random_array, get_content_from_buffer},
+    wire::{decode, is_envelope, Encoder},
+};
+
+const CONTENT_TAG: u8 = 8;
+const COUNTER_TAG: u8 = 3;
+const KIND_TAG: u8 = 2;
+const NONCE_TAG: u8 = 6;
+// Tags 4 and 5 used to carry the addresses of both peers.
+const SIGNATURE_TAG: u8 = 7;
+const VERSION_TAG: u8 = 1;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Kind {
+    Text,
+    Ack,
+    Ping,
+    Pong,
+    Typing,
+    Disconnect,
+    Fragment,
+    Heartbeat,
+}
+
+impl Kind {
+    pub fn from_u8(kind: u8) -> Result<Self, u16> {
+        match kind {
+            0 => Ok(Kind::Text),
+            1 => Ok(Kind::Ack),
+            2 => Ok(Kind::Ping),
+            3 => Ok(Kind::Pong),
+            4 => Ok(Kind::Typing),
+            5 => Ok(Kind::Disconnect),
+            6 => Ok(Kind::Fragment),
+            7 => Ok(Kind::Heartbeat),
+            _ => Err(401),
+        }
+    }
+}
+
+/// Message metadata, authenticated along with the encrypted content. The
+/// identities of both peers are known to each of them and aren't sent.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Header {
+    pub counter: u64,
+    pub kind: Kind,
+    #[serde(skip)]
+    pub recipient: String,
+    #[serde(skip)]
+    pub sender: String,
+    pub version: u8,
+}
+
+impl Header {
+    pub fn new(kind: Kind, counter: u64, sender: String, recipient: String) -> Self {
+        Header {
+            counter,
+            kind,
+            recipient,
+            sender,
+            version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Unambiguous encoding of the header, variable length fields being
+    /// prefixed by their length.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.version, self.kind as u8];
+
+        bytes.extend_from_slice(&self.counter.to_be_bytes());
+
+        for address in [&self.sender, &self.recipient].iter() {
+            bytes.extend_from_slice(&(address.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(address.as_bytes());
+        }
+
+        bytes
+    }
+}
 
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct Message {
     pub content: Vec<u8>,
+    pub header: Header,
     pub nonce: Vec<u8>,
-    pub signature: String,
+    pub signature: Vec<u8>,
 }
 
 impl Message {
-    pub fn new(content: String, key: Arc<Key>) -> Self {
-        let key_value = GenericArray::clone_from_slice(&key.get_half_key_value());
+    /// Encrypts the padded content, the padding being authenticated along
+    /// with it. The message still has to be signed.
+    pub fn new(content: &[u8], header: Header, key: &Key, padding: Padding) -> Self {
+        let key_value = GenericArray::clone_from_slice(&key.encryption);
         let aead = ChaCha20Poly1305::new(key_value);
         let nonce_array = generate_random_array();
         let nonce = GenericArray::from_slice(&nonce_array[0..NONCE_LENGTH]);
-        let ciphertext = aead
-            .encrypt(nonce, content.as_ref())
+        let padded_content = Zeroizing::new(padding.pad(content));
+        let mut message = Message {
+            content: vec![],
+            header,
+            nonce: nonce.to_vec(),
+            signature: vec![],
+        };
+
+        message.content = aead
+            .encrypt(
+                nonce,
+                aead::Payload {
+                    msg: &padded_content,
+                    aad: &message.associated_data(),
+                },
+            )
             .expect("encryption failure!");
-        let cloned_ciphertext = ciphertext.clone();
 
+        message
+    }
+
+    /// Signs the metadata along with the encrypted content.
+    pub fn sign(&mut self, key: &Key) { self.signature = key.sign_message(&self.signed_content()); }
+
+    /// Returns the message as sent from the given sender to the given
+    /// recipient, the identities of both peers not being sent.
+    pub fn bind(&self, sender: &str, recipient: &str) -> Message {
         Message {
-            content: ciphertext,
-            nonce: nonce.to_vec(),
-            signature: key.encode_message_signature(cloned_ciphertext),
+            content: self.content.clone(),
+            header: Header {
+                recipient: String::from(recipient),
+                sender: String::from(sender),
+                ..self.header.clone()
+            },
+            nonce: self.nonce.clone(),
+            signature: self.signature.clone(),
         }
     }
 
-    pub fn decrypt(&self, key: Arc<Key>) -> String {
-        let key_value = GenericArray::clone_from_slice(&key.get_half_key_value());
-        let aead = ChaCha20Poly1305::new(key_value);
+    /// Metadata which is authenticated along with the encrypted content.
+    pub fn associated_data(&self) -> Vec<u8> { self.header.to_bytes() }
 
-        let mut nonce_from_vec = [0; NONCE_LENGTH];
-        let to_array = &self.nonce[..nonce_from_vec.len()];
+    /// Content covered by the message signature.
+    pub fn signed_content(&self) -> Vec<u8> {
+        [self.associated_data(), self.content.clone()].concat()
+    }
+
+    /// Decrypts the message content, tampered or undecryptable messages
+    /// leading to an error. The padding is stripped and the plaintext wiped
+    /// once dropped.
+    pub fn decrypt(&self, key: &Key) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let key_value = GenericArray::clone_from_slice(&key.encryption);
+        let aead = ChaCha20Poly1305::new(key_value);
 
-        nonce_from_vec.copy_from_slice(&self.nonce[..to_array.len()]);
+        if self.nonce.len() != NONCE_LENGTH {
+            return Err(Error::from(108));
+        }
 
         let plaintext = aead
             .decrypt(
-                GenericArray::from_slice(&nonce_from_vec),
-                self.content.as_ref(),
+                GenericArray::from_slice(&self.nonce),
+                aead::Payload {
+                    msg: self.content.as_ref(),
+                    aad: &self.associated_data(),
+                },
             )
-            .expect("decryption failure!");
+            .map(Zeroizing::new)
+            .map_err(|_| Error::from(108))?;
+
+        unpad(&plaintext)
+            .map(|content| Zeroizing::new(content.to_vec()))
+            .map_err(Error::from)
+    }
+
+    /// Encodes the message as a binary envelope.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Encoder::default()
+            .field(VERSION_TAG, &[self.header.version])
+            .field(KIND_TAG, &[self.header.kind as u8])
+            .field(COUNTER_TAG, &self.header.counter.to_be_bytes())
+            .field(NONCE_TAG, &self.nonce)
+            .field(SIGNATURE_TAG, &self.signature)
+            .field(CONTENT_TAG, &self.content)
+            .finish()
+    }
+
+    /// Decodes a message out of a binary envelope, yet to be bound to both
+    /// peers.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Message, u16> {
+        let fields = decode(bytes)?;
+        let [version] = fields.get_array(VERSION_TAG)?;
+        let [kind] = fields.get_array(KIND_TAG)?;
 
-        str::from_utf8(&plaintext).unwrap().to_string()
+        Ok(Message {
+            content: fields.get(CONTENT_TAG)?.to_vec(),
+            header: Header {
+                counter: u64::from_be_bytes(fields.get_array(COUNTER_TAG)?),
+                kind: Kind::from_u8(kind)?,
+                recipient: String::new(),
+                sender: String::new(),
+                version,
+            },
+            nonce: fields.get(NONCE_TAG)?.to_vec(),
+            signature: fields.get(SIGNATURE_TAG)?.to_vec(),
+        })
     }
 
-    pub fn serialize(&self) -> String { serde_json::to_string(self).unwrap() }
+    /// Decodes a received message, JSON encoded messages being only accepted
+    /// in compatibility mode.
+    pub fn decode(bytes: &[u8], json_compatibility: bool) -> Result<Message, u16> {
+        if json_compatibility && !is_envelope(bytes) {
+            return Message::deserialize(get_content_from_buffer(bytes, bytes.len()))
+                .map_err(|_| 401);
+        }
+
+        Message::from_bytes(bytes)
+    }
 
     pub fn deserialize(message: String) -> Result<Message, ()> {
         match serde_json::from_str(message.as_str()) {
@@ -63,13 +234,23 @@ impl Message {
 mod utils {
     use super::*;
 
+    fn create_header(counter: u64) -> Header {
+        Header::new(
+            Kind::Text,
+            counter,
+            String::from("2001:3984:3989::10"),
+            String::from("2001:3984:3989::20"),
+        )
+    }
+
     #[test]
     fn test_message() {
         let key = Key::new(None);
-        let cloned_key = key.clone();
-        let cloned_cloned_key = key.clone();
-        let message_a = Message::new(String::from("foo"), Arc::new(key));
-        let message_b = Message::new(String::from("foo"), Arc::new(cloned_key));
+        let mut message_a = Message::new(b"foo", create_header(0), &key, Padding::Buckets);
+        let mut message_b = Message::new(b"foo", create_header(0), &key, Padding::Buckets);
+
+        message_a.sign(&key);
+        message_b.sign(&key);
 
         // Both messages' contents should be different since they are based on different
         // nonce!
@@ -86,15 +267,168 @@ mod utils {
         assert_eq!(message_a.signature.len(), message_b.signature.len());
 
         // Decrypting a message should returns its content.
+        assert_eq!(message_a.decrypt(&key), Ok(Zeroizing::new(b"foo".to_vec())));
+
+        // Encoding and decoding a message should not alter it.
         assert_eq!(
-            message_a.decrypt(Arc::new(cloned_cloned_key)),
-            String::from("foo")
+            Message::from_bytes(&message_a.to_bytes())
+                .map(|message| message.bind(&message_a.header.sender, &message_a.header.recipient)),
+            Ok(message_a)
         );
+    }
+
+    #[test]
+    fn test_message_wire() {
+        let message = Message {
+            content: vec![0xca, 0xfe],
+            header: Header::new(Kind::Text, 1, String::from("::1"), String::from("::2")),
+            nonce: vec![7; NONCE_LENGTH],
+            signature: b"sig".to_vec(),
+        };
+        let mut golden_bytes = vec![b'Y', b'U', b'M', b'E', 1, 0, 0, 0, 57];
+
+        golden_bytes.extend_from_slice(&[VERSION_TAG, 0, 0, 0, 1, PROTOCOL_VERSION]);
+        golden_bytes.extend_from_slice(&[KIND_TAG, 0, 0, 0, 1, 0]);
+        golden_bytes.extend_from_slice(&[COUNTER_TAG, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 1]);
+        golden_bytes
+            .extend_from_slice(&[NONCE_TAG, 0, 0, 0, 12, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7]);
+        golden_bytes.extend_from_slice(&[SIGNATURE_TAG, 0, 0, 0, 3, b's', b'i', b'g']);
+        golden_bytes.extend_from_slice(&[CONTENT_TAG, 0, 0, 0, 2, 0xca, 0xfe]);
 
-        // Serializing and deserializaing a message should not alter it.
+        // The wire format should not change silently, the identities of both
+        // peers being left out.
+        assert_eq!(message.to_bytes(), golden_bytes);
         assert_eq!(
-            Message::deserialize(message_a.serialize()).unwrap(),
-            message_a
+            Message::from_bytes(&golden_bytes)
+                .map(|decoded| decoded.bind("::1", "::2"))
+                .as_ref(),
+            Ok(&message)
         );
+
+        // Missing fields or unknown kinds should be rejected.
+        let mut bytes = golden_bytes[..golden_bytes.len() - 7].to_vec();
+
+        bytes[8] -= 7;
+
+        assert_eq!(Message::from_bytes(&bytes), Err(401));
+
+        let mut bytes = golden_bytes.clone();
+
+        bytes[20] = 42;
+
+        assert_eq!(Message::from_bytes(&bytes), Err(401));
+
+        // JSON encoded messages should only be accepted in compatibility mode,
+        // without the identities of both peers either.
+        let json = serde_json::to_vec(&message).unwrap();
+
+        assert!(!String::from_utf8(json.clone()).unwrap().contains("::1"));
+        assert_eq!(
+            Message::decode(&json, true).map(|decoded| decoded.bind("::1", "::2")),
+            Ok(message)
+        );
+        assert_eq!(Message::decode(&json, false), Err(401));
+        assert!(Message::decode(&golden_bytes, true).is_ok());
+    }
+
+    #[test]
+    fn test_message_padding() {
+        let key = Key::new(None);
+        let create_message =
+            |content: &[u8], padding| Message::new(content, create_header(0), &key, padding);
+
+        // Padded messages should not reveal their content length.
+        assert_eq!(
+            create_message(b"foo", Padding::Buckets).content.len(),
+            create_message(b"foobar", Padding::Buckets).content.len()
+        );
+        assert_ne!(
+            create_message(b"foo", Padding::None).content.len(),
+            create_message(b"foobar", Padding::None).content.len()
+        );
+
+        for padding in &[Padding::Buckets, Padding::None, Padding::Random] {
+            assert_eq!(
+                create_message(b"foo", *padding).decrypt(&key),
+                Ok(Zeroizing::new(b"foo".to_vec()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_message_tampering() {
+        let key = Key::new(None);
+        let mut messages = (0..5)
+            .map(|_| Message::new(b"foo", create_header(0), &key, Padding::Buckets))
+            .collect::<Vec<Message>>();
+
+        messages[0].content[0] ^= 1;
+        messages[1].content.truncate(2);
+        messages[2].nonce[0] ^= 1;
+        messages[3].nonce.truncate(NONCE_LENGTH - 1);
+        messages[4].header.counter = 1;
+
+        // Mangled messages should be rejected without panicking.
+        for message in messages {
+            assert_eq!(message.decrypt(&key), Err(Error::from(108)));
+        }
+
+        // So should be contents without a valid padding.
+        let mut message = Message::new(b"", create_header(0), &key, Padding::Buckets);
+
+        message.content = ChaCha20Poly1305::new(GenericArray::clone_from_slice(&key.encryption))
+            .encrypt(
+                GenericArray::from_slice(&message.nonce),
+                aead::Payload {
+                    msg: &[0xff, 0xfe],
+                    aad: &message.associated_data(),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(message.decrypt(&key), Err(Error::from(406)));
+
+        // Or a wrong key.
+        let message = Message::new(b"foo", create_header(0), &key, Padding::Buckets);
+
+        assert_eq!(message.decrypt(&Key::new(None)), Err(Error::from(108)));
+    }
+
+    #[test]
+    fn test_message_header() {
+        let key = Key::new(None);
+        let mut message = Message::new(b"foo", create_header(1), &key, Padding::Buckets);
+
+        message.sign(&key);
+
+        assert!(key.verify_message_signature(&message).is_ok());
+
+        // Every header field should be authenticated.
+        let mut headers = vec![create_header(1); 4];
+
+        headers[0].counter = 2;
+        headers[1].sender = String::from("2001:3984:3989::30");
+        headers[2].recipient = String::from("2001:3984:3989::30");
+        headers[3].version = PROTOCOL_VERSION + 1;
+
+        for header in headers {
+            let mut forged_message = Message::new(b"foo", create_header(1), &key, Padding::Buckets);
+
+            forged_message.sign(&key);
+            forged_message.header = header;
+
+            assert!(key.verify_message_signature(&forged_message).is_err());
+        }
+
+        // Fields should not be able to bleed into each other.
+        let mut header_a = create_header(1);
+        let mut header_b = create_header(1);
+
+        header_a.sender = String::from("a");
+        header_a.recipient = String::from("bc");
+        header_b.sender = String::from("ab");
+        header_b.recipient = String::from("c");
+
+        assert_ne!(header_a.to_bytes(), header_b.to_bytes());
     }
 }
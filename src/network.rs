@@ -0,0 +1,304 @@
+use async_std::{
+    io,
+    net::UdpSocket,
+    sync::{channel, Mutex, Receiver, Sender},
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket},
+};
+
+use yume::{
+    relay::{decode_frame, encode_frame},
+    socket,
+};
+
+use crate::peers::{canonicalize, canonicalize_address, is_reachable};
+
+/// Single UDP socket used for the key exchange, the outgoing messages and
+/// the receive loop, the latter handing the replies over to their waiters.
+pub struct Network {
+    /// Addresses seen by the remote peers met through a rendezvous server,
+    /// translated by a NAT.
+    public_addresses: HashMap<SocketAddr, SocketAddr>,
+    /// Relay forwarding all the datagrams, if the remote peer can't be reached
+    /// directly.
+    relay: Option<SocketAddr>,
+    pub socket: UdpSocket,
+    waiters: Mutex<HashMap<(SocketAddr, u64), Sender<()>>>,
+}
+
+impl Network {
+    pub fn new(socket: UdpSocket) -> Self {
+        Network {
+            public_addresses: HashMap::new(),
+            relay: None,
+            socket,
+            waiters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Binds the socket to the given local address or, if none, to the
+    /// unspecified address: a dual-stack one if any remote peer has an IPv6
+    /// address.
+    pub async fn bind(
+        local: Option<SocketAddr>,
+        remotes: &[SocketAddr],
+        port: u16,
+    ) -> Result<Self, u16> {
+        let local = local.unwrap_or_else(|| {
+            let unspecified = if remotes.iter().any(SocketAddr::is_ipv6) {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            };
+
+            SocketAddr::new(unspecified, port)
+        });
+
+        if !remotes.iter().all(|remote| is_reachable(local, *remote)) {
+            return Err(608);
+        }
+
+        let socket = socket::bind(local).map_err(|_| 205_u16)?;
+
+        Ok(Network::new(socket))
+    }
+
+    /// Sends a datagram, through the relay if any. An IPv4 address is mapped
+    /// to an IPv6 one for a dual-stack socket.
+    pub async fn send_to(&self, bytes: &[u8], address: SocketAddr) -> Result<(), u16> {
+        let (bytes, address) = match self.relay {
+            Some(relay) => (encode_frame(address, bytes), relay),
+            None => (bytes.to_vec(), address),
+        };
+
+        socket::send_to(&self.socket, &bytes, address)
+            .await
+            .map(|_| ())
+            .map_err(|_| 202)
+    }
+
+    /// Receives a datagram from a canonical origin, the ones forwarded by the
+    /// relay being unwrapped. Only the relay is listened to once in use.
+    pub async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (number_of_bytes, origin) = self.socket.recv_from(buffer).await?;
+            let origin = canonicalize_address(origin);
+            let relay = match self.relay {
+                Some(relay) => relay,
+                None => return Ok((number_of_bytes, origin)),
+            };
+
+            if origin != relay {
+                continue;
+            }
+
+            if let Some((origin, payload)) = decode_frame(&buffer[..number_of_bytes]) {
+                let (origin, length) = (canonicalize_address(origin), payload.len());
+
+                buffer.copy_within(number_of_bytes - length..number_of_bytes, 0);
+
+                return Ok((length, origin));
+            }
+        }
+    }
+
+    /// Sends and receives every datagram through the relay from now on.
+    pub fn set_relay(&mut self, relay: SocketAddr) {
+        self.relay = Some(canonicalize_address(relay));
+    }
+
+    /// Local address as seen by the given remote peer. Unless bound to a
+    /// given address, the local IP address is the one of the interface
+    /// routing to the remote peer.
+    pub fn local_address(&self, remote: SocketAddr) -> Result<SocketAddr, u16> {
+        if let Some(address) = self.public_addresses.get(&remote) {
+            return Ok(*address);
+        }
+
+        let local = self.socket.local_addr().map_err(|_| 204_u16)?;
+        let local_ip = if local.ip().is_unspecified() {
+            route(remote)?
+        } else {
+            canonicalize(local)
+        };
+
+        Ok(SocketAddr::new(local_ip, local.port()))
+    }
+
+    /// Sets the address the given remote peer sees us from, once learned from
+    /// a rendezvous server. Other peers might reach us directly.
+    pub fn set_public_address(&mut self, remote: SocketAddr, address: SocketAddr) {
+        self.public_addresses
+            .insert(canonicalize_address(remote), canonicalize_address(address));
+    }
+
+    /// Registers a waiter for the reply of a remote peer with the given id.
+    pub async fn wait(&self, remote: SocketAddr, id: u64) -> Receiver<()> {
+        let (sender, receiver) = channel(1);
+
+        self.waiters.lock().await.insert((remote, id), sender);
+
+        receiver
+    }
+
+    /// Unregisters a waiter which is no longer interested in its reply.
+    pub async fn forget(&self, remote: SocketAddr, id: u64) {
+        self.waiters.lock().await.remove(&(remote, id));
+    }
+
+    /// Hands a reply over to its waiter, if any. Late or duplicated replies
+    /// are discarded.
+    pub async fn notify(&self, remote: SocketAddr, id: u64) {
+        let waiter = self.waiters.lock().await.remove(&(remote, id));
+
+        if let Some(sender) = waiter {
+            sender.send(()).await;
+        }
+    }
+}
+
+/// Finds the local IP address the remote peer will see, by asking the system
+/// for the route to it: connecting a UDP socket doesn't send anything.
+fn route(remote: SocketAddr) -> Result<IpAddr, u16> {
+    let unspecified = match remote {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = StdUdpSocket::bind(SocketAddr::new(unspecified, 0)).map_err(|_| 204_u16)?;
+
+    socket
+        .connect(remote)
+        .and_then(|_| socket.local_addr())
+        .map(canonicalize)
+        .map_err(|_| 204)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    #[test]
+    fn test_network() {
+        task::block_on(async {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let network = Network::new(socket);
+            let remote = "127.0.0.1:3001".parse().unwrap();
+            let other_remote = "127.0.0.1:3002".parse().unwrap();
+            let receiver = network.wait(remote, 42).await;
+
+            network.notify(remote, 7).await;
+            network.notify(other_remote, 42).await;
+            network.notify(remote, 42).await;
+
+            // Only the right waiter should be notified, once.
+            assert_eq!(receiver.try_recv().ok(), Some(()));
+            assert!(network.waiters.lock().await.is_empty());
+
+            let receiver = network.wait(remote, 42).await;
+
+            network.forget(remote, 42).await;
+            network.notify(remote, 42).await;
+
+            assert!(receiver.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn test_network_relay() {
+        task::block_on(async {
+            let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let address = socket.local_addr().unwrap();
+            let remote = "127.0.0.1:3001".parse().unwrap();
+            let mut network = Network::new(socket);
+            let mut buffer = [0; 64];
+
+            network.set_relay(relay.local_addr().unwrap());
+            network.send_to(b"sealed", remote).await.unwrap();
+
+            // Datagrams should be wrapped on their way to the relay.
+            let (number_of_bytes, _) = relay.recv_from(&mut buffer).await.unwrap();
+
+            assert_eq!(
+                &buffer[..number_of_bytes],
+                &encode_frame(remote, b"sealed")[..]
+            );
+
+            // Only the datagrams forwarded by the relay should be received.
+            other
+                .send_to(&encode_frame(remote, b"forged"), address)
+                .await
+                .unwrap();
+            relay
+                .send_to(&encode_frame(remote, b"reply"), address)
+                .await
+                .unwrap();
+
+            let (number_of_bytes, origin) = network.recv_from(&mut buffer).await.unwrap();
+
+            assert_eq!(&buffer[..number_of_bytes], b"reply");
+            assert_eq!(origin, remote);
+        });
+    }
+
+    #[test]
+    fn test_network_bind() {
+        task::block_on(async {
+            let remote = "127.0.0.1:3001".parse().unwrap();
+            let other_remote = "[::1]:3001".parse().unwrap();
+            let network = Network::bind(None, &[remote], 0).await.unwrap();
+            let port = network.socket.local_addr().unwrap().port();
+
+            // The loopback interface routes to a loopback peer.
+            assert_eq!(
+                network.local_address(remote),
+                Ok(SocketAddr::new("127.0.0.1".parse().unwrap(), port))
+            );
+            assert!(network.socket.local_addr().unwrap().is_ipv4());
+
+            // A single dual-stack socket should reach peers of both families,
+            // each of them seeing us from its own family.
+            let mut network = Network::bind(None, &[remote, other_remote], 0)
+                .await
+                .unwrap();
+            let port = network.socket.local_addr().unwrap().port();
+
+            assert!(network.socket.local_addr().unwrap().ip().is_unspecified());
+            assert_eq!(
+                network.local_address(remote),
+                Ok(SocketAddr::new("127.0.0.1".parse().unwrap(), port))
+            );
+            assert_eq!(
+                network.local_address(other_remote),
+                Ok(SocketAddr::new("::1".parse().unwrap(), port))
+            );
+
+            // Only the peer met through a rendezvous server should see our
+            // public address.
+            network.set_public_address(remote, "203.0.113.1:4001".parse().unwrap());
+
+            assert_eq!(
+                network.local_address(remote),
+                Ok("203.0.113.1:4001".parse().unwrap())
+            );
+            assert_eq!(
+                network.local_address(other_remote),
+                Ok(SocketAddr::new("::1".parse().unwrap(), port))
+            );
+            assert_eq!(
+                Network::bind(
+                    Some("127.0.0.1:0".parse().unwrap()),
+                    &[remote, other_remote],
+                    0
+                )
+                .await
+                .map(|_| ()),
+                Err(608)
+            );
+        });
+    }
+}
@@ -0,0 +1,102 @@
+use std::{convert::TryInto, str::FromStr};
+
+use crate::utils::generate_random_array;
+
+const BUCKETS: [usize; 3] = [64, 256, 1024];
+const LENGTH_PREFIX: usize = 4;
+
+/// Padding applied to the content before its encryption, so that the
+/// ciphertext doesn't reveal the exact content length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Padding {
+    /// Pads to the next bucket of 64, 256 or 1024 bytes, then to the next
+    /// multiple of 1024 bytes.
+    Buckets,
+    /// Only prefixes the content with its length.
+    None,
+    /// Pads with a random amount of up to 255 bytes.
+    Random,
+}
+
+impl Padding {
+    /// Prefixes the content with its length and pads it with zeros.
+    pub fn pad(self, content: &[u8]) -> Vec<u8> {
+        let length = LENGTH_PREFIX + content.len();
+        let padded_length = match self {
+            Padding::Buckets => {
+                let last_bucket = BUCKETS[BUCKETS.len() - 1];
+
+                BUCKETS
+                    .iter()
+                    .copied()
+                    .find(|bucket| *bucket >= length)
+                    .unwrap_or_else(|| length.div_ceil(last_bucket) * last_bucket)
+            }
+            Padding::None => length,
+            Padding::Random => length + generate_random_array()[0] as usize,
+        };
+        let mut padded_content = Vec::with_capacity(padded_length);
+
+        padded_content.extend_from_slice(&(content.len() as u32).to_be_bytes());
+        padded_content.extend_from_slice(content);
+        padded_content.resize(padded_length, 0);
+
+        padded_content
+    }
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(padding: &str) -> Result<Self, Self::Err> {
+        match padding {
+            "buckets" => Ok(Padding::Buckets),
+            "none" => Ok(Padding::None),
+            "random" => Ok(Padding::Random),
+            _ => Err(format!("unknown padding {}", padding)),
+        }
+    }
+}
+
+/// Strips the padding, returning the original content.
+pub fn unpad(padded_content: &[u8]) -> Result<&[u8], u16> {
+    if padded_content.len() < LENGTH_PREFIX {
+        return Err(406);
+    }
+
+    let (length, rest) = padded_content.split_at(LENGTH_PREFIX);
+    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+
+    rest.get(..length).ok_or(406)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(Padding::Buckets.pad(b"").len(), 64);
+        assert_eq!(Padding::Buckets.pad(&[1; 60]).len(), 64);
+        assert_eq!(Padding::Buckets.pad(&[1; 61]).len(), 256);
+        assert_eq!(Padding::Buckets.pad(&[1; 300]).len(), 1024);
+        assert_eq!(Padding::Buckets.pad(&[1; 1021]).len(), 2048);
+        assert_eq!(Padding::None.pad(b"foo").len(), 7);
+        assert!(Padding::Random.pad(b"foo").len() < 7 + 256);
+
+        // The content should survive every padding.
+        for padding in &[Padding::Buckets, Padding::None, Padding::Random] {
+            assert_eq!(unpad(&padding.pad(b"foo")), Ok(&b"foo"[..]));
+        }
+
+        assert_eq!("random".parse(), Ok(Padding::Random));
+        assert!("foo".parse::<Padding>().is_err());
+    }
+
+    #[test]
+    fn test_padding_malformed() {
+        assert_eq!(unpad(&[0, 0, 0]), Err(406));
+        assert_eq!(unpad(&[0, 0, 0, 4, 1, 2, 3]), Err(406));
+        assert_eq!(unpad(&[0, 0, 0, 0]), Ok(&[][..]));
+    }
+}
@@ -1,48 +1,284 @@
+use async_std::net::ToSocketAddrs;
 use crossterm::{execute, style};
-use std::io::{stdout, Write};
+use std::{
+    io::{stdout, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+};
 
+/// Remote peers of a conversation, a single one unless chatting in a group.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Peers {
-    pub local: String,
-    pub remote: String,
+    pub remotes: Vec<SocketAddr>,
 }
 
 impl Peers {
-    pub fn new(local: String, remote: String) -> Self {
-        Peers { local, remote }
+    pub fn new(remotes: Vec<SocketAddr>) -> Self { Peers { remotes } }
+
+    /// Resolves the remote peer addresses, preferring ones reachable from the
+    /// local address if any.
+    pub async fn resolve(
+        remotes: &[String],
+        port: u16,
+        local: Option<SocketAddr>,
+    ) -> Result<Self, u16> {
+        let mut addresses = vec![];
+
+        for remote in remotes {
+            let remote = canonicalize_address(resolve(remote, port, local).await?);
+
+            if local.is_some_and(|local| !is_reachable(local, remote)) {
+                return Err(608);
+            }
+
+            if addresses.contains(&remote) {
+                return Err(609);
+            }
+
+            addresses.push(remote);
+        }
+
+        Ok(Peers::new(addresses))
+    }
+
+    pub fn contains(&self, address: SocketAddr) -> bool { self.remotes.contains(&address) }
+
+    pub fn is_group(&self) -> bool { self.remotes.len() > 1 }
+
+    /// Short name of a remote peer: its IP address, along with its port if
+    /// another peer shares the same IP address.
+    pub fn name(&self, address: SocketAddr) -> String {
+        let is_ambiguous = self
+            .remotes
+            .iter()
+            .any(|remote| *remote != address && remote.ip() == address.ip());
+
+        if is_ambiguous {
+            address.to_string()
+        } else {
+            canonicalize(address).to_string()
+        }
     }
 
-    pub fn display_remote(&self) {
+    pub fn display_remote(&self, address: SocketAddr) {
         execute!(
             stdout(),
             style::SetForegroundColor(style::Color::DarkMagenta),
-            style::Print(format!("{} ", self.local.clone())),
+            style::Print(format!("{} ", self.name(address))),
             style::SetForegroundColor(style::Color::White)
         )
         .unwrap();
     }
 }
 
+/// Both peers need to agree on the textual representation of an address,
+/// IPv4-mapped IPv6 addresses coming from dual-stack sockets being turned
+/// back into IPv4 ones.
+pub fn canonicalize(address: SocketAddr) -> IpAddr {
+    match address.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Same as `canonicalize`, the port and the zone ID being kept.
+pub use yume::socket::canonicalize_address;
+
+/// Identity of a peer both ends agree on: its canonical IP address along with
+/// its port, the zone ID only making sense on the host it was given on.
+pub fn identify(address: SocketAddr) -> String {
+    SocketAddr::new(canonicalize(address), address.port()).to_string()
+}
+
+/// An IPv4 socket can't reach an IPv6 peer, an IPv6 one can only reach an
+/// IPv4 peer if bound to the unspecified address, which is dual-stack.
+pub fn is_reachable(local: SocketAddr, remote: SocketAddr) -> bool {
+    local.is_ipv4() == remote.is_ipv4() || (local.is_ipv6() && local.ip().is_unspecified())
+}
+
+/// Splits an address into its host and its optional port: IPv6 addresses
+/// need brackets to be given along with a port.
+fn split_host_port(address: &str) -> Result<(&str, Option<u16>), u16> {
+    let parse_port = |port: &str| port.parse::<u16>().ok().filter(|port| *port != 0);
+
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        match rest.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => (
+                host,
+                Some(port.strip_prefix(':').and_then(parse_port).ok_or(603_u16)?),
+            ),
+            None => return Err(603),
+        }
+    } else if address.matches(':').count() > 1 {
+        (address, None)
+    } else if let Some((host, port)) = address.split_once(':') {
+        (host, Some(parse_port(port).ok_or(603_u16)?))
+    } else {
+        (address, None)
+    };
+
+    if host.is_empty() {
+        return Err(603);
+    }
+
+    Ok((host, port))
+}
+
+/// Resolves an address, given as an IP address or a hostname, either bare or
+/// along with a port, via the system resolver (hence `/etc/hosts`) if it
+/// isn't an IP address.
+pub async fn resolve(
+    address: &str,
+    default_port: u16,
+    local: Option<SocketAddr>,
+) -> Result<SocketAddr, u16> {
+    let (host, port) = split_host_port(address)?;
+    let port = port.unwrap_or(default_port);
+
+    // IPv6 link-local addresses need a zone ID.
+    if let Some((ip, zone)) = host.split_once('%') {
+        let ip = ip.parse::<Ipv6Addr>().map_err(|_| 603_u16)?;
+
+        return Ok(SocketAddr::V6(SocketAddrV6::new(
+            ip,
+            port,
+            0,
+            get_zone_index(zone)?,
+        )));
+    }
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    let addresses = (host, port)
+        .to_socket_addrs()
+        .await
+        .map_err(|_| 607_u16)?
+        .collect::<Vec<SocketAddr>>();
+
+    addresses
+        .iter()
+        .find(|address| local.is_none_or(|local| is_reachable(local, **address)))
+        .or_else(|| addresses.first())
+        .copied()
+        .ok_or(607)
+}
+
+/// Returns the index of a network interface given by its index or its name.
+pub fn get_zone_index(zone: &str) -> Result<u32, u16> {
+    if let Ok(index) = zone.parse::<u32>() {
+        return Ok(index);
+    }
+
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(zone).map_err(|_| 603_u16)?;
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+
+        if index != 0 {
+            return Ok(index);
+        }
+    }
+
+    Err(603)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use async_std::task;
+
+    fn resolve_address(address: &str) -> Result<SocketAddr, u16> {
+        task::block_on(resolve(address, 3001, None))
+    }
+
+    fn resolve_peers(remotes: &[&str], local: &str) -> Result<Peers, u16> {
+        let remotes = remotes
+            .iter()
+            .map(|remote| remote.to_string())
+            .collect::<Vec<String>>();
+
+        task::block_on(Peers::resolve(&remotes, 3001, Some(local.parse().unwrap())))
+    }
 
     #[test]
     fn check_peers() {
-        let local_peer = String::from("2001:3984:3989::10");
-        let remote_peer = String::from("2001:3984:3989::20");
-        let local_peer_clone = local_peer.clone();
-        let remote_peer_clone = remote_peer.clone();
-        let peers = Peers::new(local_peer, remote_peer);
+        let peers = resolve_peers(&["[2001:3984:3989::20]:4000"], "[::]:3001").unwrap();
+        let remote = "[2001:3984:3989::20]:4000".parse().unwrap();
+
+        assert_eq!(peers, Peers::new(vec![remote]));
+        assert!(peers.contains(remote));
+        assert!(!peers.is_group());
+        assert_eq!(peers.name(remote), "2001:3984:3989::20");
+        assert_eq!(peers.display_remote(remote), ());
+    }
+
+    #[test]
+    fn check_peers_group() {
+        let peers =
+            resolve_peers(&["::1", "[::1]:3002", "::ffff:192.168.1.20"], "[::]:3001").unwrap();
+
+        assert!(peers.is_group());
+        assert_eq!(peers.remotes[2], "192.168.1.20:3001".parse().unwrap());
+
+        // Peers sharing the same IP address should be told apart.
+        assert_eq!(peers.name(peers.remotes[1]), "[::1]:3002");
+        assert_eq!(peers.name(peers.remotes[2]), "192.168.1.20");
+        assert_eq!(resolve_peers(&["::1", "[::1]:3001"], "[::]:3001"), Err(609));
+    }
 
+    #[test]
+    fn check_peers_addresses() {
         assert_eq!(
-            peers,
-            Peers {
-                local: local_peer_clone,
-                remote: remote_peer_clone
-            }
+            resolve_address("192.168.1.20:4000"),
+            Ok("192.168.1.20:4000".parse().unwrap())
+        );
+        assert_eq!(
+            resolve_address("2001:3984:3989::20"),
+            Ok("[2001:3984:3989::20]:3001".parse().unwrap())
         );
 
-        assert_eq!(peers.display_remote(), ());
+        match resolve_address("[fe80::20%1]:4000") {
+            Ok(SocketAddr::V6(address)) => assert_eq!(address.scope_id(), 1),
+            _ => panic!("not an IPv6 address"),
+        }
+
+        assert_eq!(resolve_address("localhost").map(|_| ()), Ok(()));
+
+        // A dual-stack socket should reach IPv4 peers, unlike IPv6 ones.
+        assert!(resolve_peers(&["192.168.1.20"], "[::]:3001").is_ok());
+        assert_eq!(resolve_peers(&["192.168.1.20"], "[::1]:3001"), Err(608));
+        assert_eq!(resolve_peers(&["::1"], "127.0.0.1:3001"), Err(608));
+    }
+
+    #[test]
+    fn check_peers_malformed() {
+        let addresses = [
+            "[::1]:",
+            "[::1]:0",
+            "[::1]:foo",
+            "[::1",
+            "[]:4000",
+            "192.168.1.10:",
+            "fe80::10%",
+            "192.168.1.10%1",
+        ];
+
+        for address in &addresses {
+            assert_eq!(resolve_address(address), Err(603));
+        }
+    }
+
+    #[test]
+    fn check_canonicalize() {
+        assert_eq!(
+            canonicalize("[2001:3984:3989:0::10]:3001".parse().unwrap()).to_string(),
+            "2001:3984:3989::10"
+        );
+        assert_eq!(
+            canonicalize_address("[::ffff:192.168.1.10]:3001".parse().unwrap()),
+            "192.168.1.10:3001".parse().unwrap()
+        );
     }
 }
@@ -0,0 +1,212 @@
+use crossterm::{execute, style};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{stdout, Write},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::config::{IDLE_TIMEOUT, MISSED_HEARTBEATS};
+
+/// Connection state of a remote peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    /// Nothing heard from the peer since the key exchange yet.
+    Connecting,
+    Online,
+    /// Reachable, but its user didn't touch the keyboard for a while.
+    Idle,
+    /// Several heartbeats were missed, the peer might still come back.
+    Unreachable,
+    /// The peer left the conversation, for good.
+    Left,
+}
+
+impl State {
+    pub fn render(self) {
+        let color = match self {
+            State::Connecting | State::Left => style::Color::DarkGrey,
+            State::Online => style::Color::DarkGreen,
+            State::Idle => style::Color::DarkYellow,
+            State::Unreachable => style::Color::DarkRed,
+        };
+
+        execute!(
+            stdout(),
+            style::SetForegroundColor(color),
+            style::Print(self),
+            style::SetForegroundColor(style::Color::White)
+        )
+        .unwrap();
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            State::Connecting => "connecting",
+            State::Online => "online",
+            State::Idle => "idle",
+            State::Unreachable => "unreachable",
+            State::Left => "left",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct PeerPresence {
+    state: State,
+    last_seen: Instant,
+}
+
+/// State of every remote peer, along with the activity of the local user
+/// which is advertised in our heartbeats.
+#[derive(Debug)]
+pub struct Presence {
+    peers: HashMap<SocketAddr, PeerPresence>,
+    last_activity: Instant,
+    unreachable_timeout: Duration,
+}
+
+impl Presence {
+    pub fn new(remotes: &[SocketAddr], heartbeat_interval: Duration, now: Instant) -> Self {
+        Presence {
+            peers: remotes
+                .iter()
+                .map(|remote| {
+                    (
+                        *remote,
+                        PeerPresence {
+                            state: State::Connecting,
+                            last_seen: now,
+                        },
+                    )
+                })
+                .collect(),
+            last_activity: now,
+            unreachable_timeout: heartbeat_interval * MISSED_HEARTBEATS,
+        }
+    }
+
+    pub fn state(&self, remote: SocketAddr) -> Option<State> {
+        self.peers.get(&remote).map(|peer| peer.state)
+    }
+
+    /// Records an authenticated message from a remote peer, heartbeats and
+    /// typed messages telling whether its user is idle. Returns the new state
+    /// of the peer if it changed.
+    pub fn seen(
+        &mut self,
+        remote: SocketAddr,
+        is_idle: Option<bool>,
+        now: Instant,
+    ) -> Option<State> {
+        let peer = self.peers.get_mut(&remote)?;
+        let state = match (is_idle, peer.state) {
+            // A peer which left doesn't come back within the same session.
+            (_, State::Left) => return None,
+            (Some(true), _) | (None, State::Idle) => State::Idle,
+            _ => State::Online,
+        };
+
+        peer.last_seen = now;
+
+        update(peer, state)
+    }
+
+    pub fn leave(&mut self, remote: SocketAddr) -> Option<State> {
+        self.peers
+            .get_mut(&remote)
+            .and_then(|peer| update(peer, State::Left))
+    }
+
+    /// Marks the peers not heard from for too long as unreachable, returning
+    /// them.
+    pub fn expire(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let timeout = self.unreachable_timeout;
+        let mut expired: Vec<SocketAddr> = self
+            .peers
+            .iter_mut()
+            .filter(|(_, peer)| {
+                peer.state != State::Left && now.duration_since(peer.last_seen) >= timeout
+            })
+            .filter_map(|(remote, peer)| update(peer, State::Unreachable).map(|_| *remote))
+            .collect();
+
+        expired.sort();
+
+        expired
+    }
+
+    /// Records a key pressed by the local user.
+    pub fn touch(&mut self, now: Instant) { self.last_activity = now; }
+
+    pub fn is_idle(&self, now: Instant) -> bool {
+        now.duration_since(self.last_activity) >= Duration::from_secs(IDLE_TIMEOUT)
+    }
+}
+
+fn update(peer: &mut PeerPresence, state: State) -> Option<State> {
+    if peer.state == state {
+        return None;
+    }
+
+    peer.state = state;
+
+    Some(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presence() {
+        let a = "[2001:3984:3989::20]:3001".parse().unwrap();
+        let b = "192.168.1.40:3001".parse().unwrap();
+        let interval = Duration::from_secs(5);
+        let now = Instant::now();
+        let mut presence = Presence::new(&[a, b], interval, now);
+
+        assert_eq!(presence.state(a), Some(State::Connecting));
+        assert_eq!(presence.seen(a, Some(false), now), Some(State::Online));
+        assert_eq!(presence.seen(a, None, now), None);
+        assert_eq!(presence.seen(a, Some(true), now), Some(State::Idle));
+        // Acks and pongs don't tell anything about the user.
+        assert_eq!(presence.seen(a, None, now), None);
+        assert_eq!(presence.seen(a, Some(false), now), Some(State::Online));
+
+        // Peers missing several heartbeats should become unreachable, once.
+        let later = now + interval * MISSED_HEARTBEATS;
+
+        assert_eq!(presence.expire(later - interval), vec![]);
+        assert_eq!(presence.expire(later), vec![b, a]);
+        assert_eq!(presence.expire(later), vec![]);
+        assert_eq!(presence.seen(a, Some(true), later), Some(State::Idle));
+        assert_eq!(presence.seen(b, None, later), Some(State::Online));
+
+        // Peers which left should stay gone.
+        assert_eq!(presence.leave(b), Some(State::Left));
+        assert_eq!(presence.seen(b, Some(false), later), None);
+        assert_eq!(
+            presence.expire(later + interval * MISSED_HEARTBEATS),
+            vec![a]
+        );
+        assert_eq!(presence.state(b), Some(State::Left));
+    }
+
+    #[test]
+    fn test_activity() {
+        let now = Instant::now();
+        let mut presence = Presence::new(&[], Duration::from_secs(5), now);
+        let later = now + Duration::from_secs(IDLE_TIMEOUT);
+
+        assert!(!presence.is_idle(now));
+        assert!(presence.is_idle(later));
+
+        presence.touch(later);
+
+        assert!(!presence.is_idle(later));
+    }
+}
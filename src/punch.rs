@@ -0,0 +1,128 @@
+use async_std::io;
+use ring::digest;
+use std::{net::SocketAddr, time::Instant};
+use yume::rendezvous::Rendezvous;
+
+use crate::{network::Network, peers::canonicalize_address, settings::Settings, terminal::println};
+
+const ROOM_LABEL: &[u8] = b"yume room";
+
+/// The rendezvous server only gets a digest of the room name.
+fn get_room_token(room: &str) -> String {
+    let mut context = digest::Context::new(&digest::SHA256);
+
+    context.update(ROOM_LABEL);
+    context.update(room.as_bytes());
+
+    context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Registers with the rendezvous server until a remote peer joins the same
+/// room, returning our public address along with the remote peer's one.
+/// Registering from the conversation socket opens the NAT mapping the key
+/// exchange then goes through, both peers sending to each other at once.
+pub async fn rendezvous(
+    network: &Network,
+    settings: &Settings,
+    server: SocketAddr,
+    room: &str,
+) -> Result<(SocketAddr, SocketAddr), u16> {
+    let register = Rendezvous::Register {
+        room: get_room_token(room),
+    }
+    .to_bytes();
+    let server = canonicalize_address(server);
+    let mut buffer = vec![0u8; settings.buffer_size];
+    let mut is_registered = false;
+
+    println(format!("Meeting other peers at {}...", server), true);
+
+    for attempt in 0..settings.handshake_attempts {
+        // Don't wait for a peer if the server can't be reached.
+        if !is_registered && attempt >= u64::from(settings.max_attempts) {
+            return Err(208);
+        }
+
+        let _ = network.send_to(&register, server).await;
+        let deadline = Instant::now() + settings.handshake_interval;
+
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            let (number_of_bytes, origin) =
+                match io::timeout(timeout, network.socket.recv_from(&mut buffer)).await {
+                    Ok(received) => received,
+                    Err(_) => break,
+                };
+
+            if canonicalize_address(origin) != server {
+                continue;
+            }
+
+            if let Some(Rendezvous::Registered { address, peers }) =
+                Rendezvous::from_bytes(&buffer[..number_of_bytes])
+            {
+                is_registered = true;
+
+                if let Some(remote) = peers.first() {
+                    return Ok((canonicalize_address(address), canonicalize_address(*remote)));
+                }
+            }
+        }
+    }
+
+    Err(if is_registered { 209 } else { 208 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::{net::UdpSocket, task};
+    use std::time::Duration;
+    use yume::rendezvous::{serve, REGISTRATION_TIMEOUT};
+
+    #[test]
+    fn test_rendezvous() {
+        task::block_on(async {
+            // A local server stands in for the rendezvous server.
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let server = socket.local_addr().unwrap();
+
+            task::spawn(serve(socket, Duration::from_secs(REGISTRATION_TIMEOUT)));
+
+            let settings = Settings::default();
+            let local = Some("127.0.0.1:0".parse().unwrap());
+            let a = Network::bind(local, &[server], 0).await.unwrap();
+            let b = Network::bind(local, &[server], 0).await.unwrap();
+            let other = Network::bind(local, &[server], 0).await.unwrap();
+            let (a_addresses, b_addresses) = futures::join!(
+                rendezvous(&a, &settings, server, "room"),
+                rendezvous(&b, &settings, server, "room")
+            );
+            let a_address = a.socket.local_addr().unwrap();
+            let b_address = b.socket.local_addr().unwrap();
+
+            // Both peers should be introduced to each other.
+            assert_eq!(a_addresses, Ok((a_address, b_address)));
+            assert_eq!(b_addresses, Ok((b_address, a_address)));
+
+            let settings = Settings {
+                handshake_attempts: 2,
+                handshake_interval: Duration::from_millis(100),
+                ..Settings::default()
+            };
+
+            assert_eq!(
+                rendezvous(&other, &settings, server, "other room").await,
+                Err(209)
+            );
+            assert_eq!(
+                rendezvous(&other, &settings, a_address, "room").await,
+                Err(208)
+            );
+        });
+    }
+}
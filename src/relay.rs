@@ -0,0 +1,180 @@
+use async_std::{io, net::UdpSocket};
+use std::{
+    net::SocketAddr,
+    str,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    rendezvous::{introduce, Rendezvous, Rooms},
+    socket::{canonicalize_address, send_to},
+};
+
+pub const RELAY_PORT: u16 = 3004;
+
+const FRAME_MAGIC: &[u8] = b"yrly";
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+/// Wraps a datagram forwarded by the relay: the address is the one of the
+/// recipient on the way to the relay and the one of the sender on the way
+/// back.
+pub fn encode_frame(address: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let address = address.to_string();
+    let mut frame = Vec::with_capacity(FRAME_MAGIC.len() + 1 + address.len() + payload.len());
+
+    frame.extend_from_slice(FRAME_MAGIC);
+    frame.push(address.len() as u8);
+    frame.extend_from_slice(address.as_bytes());
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+pub fn decode_frame(frame: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    let frame = frame.strip_prefix(FRAME_MAGIC)?;
+    let (length, frame) = frame.split_first()?;
+
+    if frame.len() < usize::from(*length) {
+        return None;
+    }
+
+    let (address, payload) = frame.split_at(usize::from(*length));
+    let address = str::from_utf8(address).ok()?.parse().ok()?;
+
+    Some((address, payload))
+}
+
+/// Runs the relay: peers register the same way as with the rendezvous server
+/// and get their datagrams forwarded to the other peers of their room. Those
+/// are sealed envelopes and handshakes, which the relay can't open.
+pub async fn serve(socket: UdpSocket, timeout: Duration) -> io::Result<()> {
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    let mut rooms = Rooms::new(timeout);
+
+    loop {
+        let (number_of_bytes, origin) = socket.recv_from(&mut buffer).await?;
+        let origin = canonicalize_address(origin);
+        let datagram = &buffer[..number_of_bytes];
+
+        if let Some((recipient, payload)) = decode_frame(datagram) {
+            // Only peers which met in a room can talk to each other.
+            if rooms.are_together(origin, recipient, Instant::now()) {
+                let _ = send_to(&socket, &encode_frame(origin, payload), recipient).await;
+            }
+        } else if let Some(Rendezvous::Register { room }) = Rendezvous::from_bytes(datagram) {
+            introduce(&socket, &mut rooms, &room, origin).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendezvous::REGISTRATION_TIMEOUT;
+    use async_std::task;
+
+    async fn receive(socket: &UdpSocket) -> Vec<u8> {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        let (number_of_bytes, _) =
+            io::timeout(Duration::from_secs(1), socket.recv_from(&mut buffer))
+                .await
+                .unwrap();
+
+        buffer.truncate(number_of_bytes);
+
+        buffer
+    }
+
+    #[test]
+    fn test_frame() {
+        let address = "[2001:3984:3989::20]:3001".parse().unwrap();
+        let frame = encode_frame(address, b"sealed");
+
+        assert_eq!(decode_frame(&frame), Some((address, &b"sealed"[..])));
+        assert_eq!(decode_frame(&frame[..10]), None);
+        assert_eq!(decode_frame(br#"{"type":"register"}"#), None);
+    }
+
+    #[test]
+    fn test_relay() {
+        task::block_on(async {
+            let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let relay_address = relay.local_addr().unwrap();
+
+            task::spawn(serve(relay, Duration::from_secs(REGISTRATION_TIMEOUT)));
+
+            let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let c = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let (a_address, b_address) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+            let register = Rendezvous::Register {
+                room: String::from("room"),
+            };
+
+            // Peers which didn't meet in a room should be ignored.
+            c.send_to(&encode_frame(b_address, b"intruder"), relay_address)
+                .await
+                .unwrap();
+
+            for socket in &[&a, &b] {
+                socket
+                    .send_to(&register.to_bytes(), relay_address)
+                    .await
+                    .unwrap();
+
+                assert!(Rendezvous::from_bytes(&receive(socket).await).is_some());
+            }
+
+            // The first peer gets introduced to the second one.
+            assert!(Rendezvous::from_bytes(&receive(&a).await).is_some());
+            a.send_to(&encode_frame(b_address, b"sealed"), relay_address)
+                .await
+                .unwrap();
+
+            assert_eq!(receive(&b).await, encode_frame(a_address, b"sealed"));
+        });
+    }
+
+    #[test]
+    fn test_relay_dual_stack() {
+        task::block_on(async {
+            let relay = crate::socket::bind("[::]:0".parse().unwrap()).unwrap();
+            let relay_address: SocketAddr =
+                ([127, 0, 0, 1], relay.local_addr().unwrap().port()).into();
+
+            task::spawn(serve(relay, Duration::from_secs(REGISTRATION_TIMEOUT)));
+
+            let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let (a_address, b_address) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+            let register = Rendezvous::Register {
+                room: String::from("room"),
+            };
+
+            for socket in &[&a, &b] {
+                socket
+                    .send_to(&register.to_bytes(), relay_address)
+                    .await
+                    .unwrap();
+
+                assert!(Rendezvous::from_bytes(&receive(socket).await).is_some());
+            }
+
+            // IPv4 peers should be told about their IPv4 addresses.
+            assert_eq!(
+                Rendezvous::from_bytes(&receive(&a).await),
+                Some(Rendezvous::Registered {
+                    address: a_address,
+                    peers: vec![b_address],
+                })
+            );
+
+            // IPv4 peers should talk to each other through a dual-stack relay.
+            a.send_to(&encode_frame(b_address, b"sealed"), relay_address)
+                .await
+                .unwrap();
+
+            assert_eq!(receive(&b).await, encode_frame(a_address, b"sealed"));
+        });
+    }
+}
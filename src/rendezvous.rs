@@ -0,0 +1,250 @@
+use async_std::{io, net::UdpSocket};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::socket::{canonicalize_address, send_to};
+
+pub const RENDEZVOUS_PORT: u16 = 3003;
+pub const REGISTRATION_TIMEOUT: u64 = 30;
+
+const BUFFER_SIZE: usize = 1024;
+const MAX_ROOM_SIZE: usize = 16;
+
+/// Datagrams exchanged with the rendezvous server.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub enum Rendezvous {
+    /// Sent by a peer over and over, keeping its NAT mapping open. The room
+    /// is an opaque token both peers agreed upon.
+    Register { room: String },
+    /// Sent back by the server: the public address of the peer along with the
+    /// ones of the other peers of its room.
+    Registered {
+        address: SocketAddr,
+        peers: Vec<SocketAddr>,
+    },
+}
+
+impl Rendezvous {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> { serde_json::from_slice(bytes).ok() }
+
+    pub fn to_bytes(&self) -> Vec<u8> { serde_json::to_vec(self).unwrap() }
+}
+
+/// Peers registered in every room, forgotten once they stop registering.
+pub struct Rooms {
+    rooms: HashMap<String, HashMap<SocketAddr, Instant>>,
+    timeout: Duration,
+}
+
+impl Rooms {
+    pub fn new(timeout: Duration) -> Self {
+        Rooms {
+            rooms: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Registers a peer and tells whether it just joined its room, if not
+    /// full.
+    pub fn register(&mut self, room: &str, address: SocketAddr, now: Instant) -> Option<bool> {
+        let timeout = self.timeout;
+
+        self.rooms.retain(|_, peers| {
+            peers.retain(|_, last_seen| now.duration_since(*last_seen) < timeout);
+
+            !peers.is_empty()
+        });
+
+        let peers = self.rooms.entry(String::from(room)).or_default();
+        let is_new = !peers.contains_key(&address);
+
+        if is_new && peers.len() >= MAX_ROOM_SIZE {
+            return None;
+        }
+
+        peers.insert(address, now);
+
+        Some(is_new)
+    }
+
+    /// Tells whether both peers are in the same room, their registration being
+    /// renewed as long as they talk to each other.
+    pub fn are_together(&mut self, a: SocketAddr, b: SocketAddr, now: Instant) -> bool {
+        let timeout = self.timeout;
+
+        self.rooms.values_mut().any(|peers| {
+            let is_registered = |address| {
+                peers
+                    .get(&address)
+                    .is_some_and(|last_seen| now.duration_since(*last_seen) < timeout)
+            };
+
+            if a != b && is_registered(a) && is_registered(b) {
+                peers.insert(a, now);
+                peers.insert(b, now);
+
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Addresses of the peers of a room, but the given one.
+    pub fn others(&self, room: &str, address: SocketAddr) -> Vec<SocketAddr> {
+        let mut others = self.rooms.get(room).map_or(vec![], |peers| {
+            peers
+                .keys()
+                .filter(|peer| **peer != address)
+                .copied()
+                .collect()
+        });
+
+        others.sort();
+
+        others
+    }
+}
+
+/// Registers a peer: it learns its public address and the ones of the other
+/// peers of its room, which are told about a newcomer right away so that all
+/// of them start punching holes at the same time.
+pub async fn introduce(socket: &UdpSocket, rooms: &mut Rooms, room: &str, origin: SocketAddr) {
+    let is_new = match rooms.register(room, origin, Instant::now()) {
+        Some(is_new) => is_new,
+        None => return,
+    };
+    let recipients = if is_new {
+        rooms
+            .others(room, origin)
+            .into_iter()
+            .chain(Some(origin))
+            .collect()
+    } else {
+        vec![origin]
+    };
+
+    // Peers can be gone already, nothing to do about it.
+    for recipient in recipients {
+        let registered = Rendezvous::Registered {
+            address: recipient,
+            peers: rooms.others(room, recipient),
+        };
+        let _ = send_to(socket, &registered.to_bytes(), recipient).await;
+    }
+}
+
+/// Runs the rendezvous server.
+pub async fn serve(socket: UdpSocket, timeout: Duration) -> io::Result<()> {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rooms = Rooms::new(timeout);
+
+    loop {
+        let (number_of_bytes, origin) = socket.recv_from(&mut buffer).await?;
+        let origin = canonicalize_address(origin);
+
+        if let Some(Rendezvous::Register { room }) =
+            Rendezvous::from_bytes(&buffer[..number_of_bytes])
+        {
+            introduce(&socket, &mut rooms, &room, origin).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    async fn receive(socket: &UdpSocket) -> Rendezvous {
+        let mut buffer = [0; BUFFER_SIZE];
+        let (number_of_bytes, _) =
+            io::timeout(Duration::from_secs(1), socket.recv_from(&mut buffer))
+                .await
+                .unwrap();
+
+        Rendezvous::from_bytes(&buffer[..number_of_bytes]).unwrap()
+    }
+
+    #[test]
+    fn test_rooms() {
+        let mut rooms = Rooms::new(Duration::from_secs(REGISTRATION_TIMEOUT));
+        let now = Instant::now();
+        let a = "192.168.1.10:3001".parse().unwrap();
+        let b = "[2001:3984:3989::20]:4000".parse().unwrap();
+
+        assert_eq!(rooms.register("room", a, now), Some(true));
+        assert_eq!(rooms.register("room", a, now), Some(false));
+        assert_eq!(rooms.register("room", b, now), Some(true));
+        assert_eq!(rooms.register("other room", b, now), Some(true));
+        assert_eq!(rooms.others("room", a), vec![b]);
+        assert_eq!(rooms.others("other room", b), vec![]);
+        assert!(rooms.are_together(a, b, now));
+        assert!(!rooms.are_together(a, a, now));
+
+        // Peers which stopped registering should be forgotten.
+        let later = now + Duration::from_secs(REGISTRATION_TIMEOUT);
+
+        assert_eq!(rooms.register("room", a, later), Some(true));
+        assert_eq!(rooms.others("room", a), vec![]);
+
+        for port in 1..MAX_ROOM_SIZE as u16 {
+            rooms.register("room", SocketAddr::new(a.ip(), port), later);
+        }
+
+        // A full room should be left alone.
+        assert_eq!(rooms.register("room", b, later), None);
+    }
+
+    #[test]
+    fn test_serve() {
+        task::block_on(async {
+            let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let server_address = server.local_addr().unwrap();
+
+            task::spawn(serve(server, Duration::from_secs(REGISTRATION_TIMEOUT)));
+
+            let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let register = Rendezvous::Register {
+                room: String::from("room"),
+            };
+            a.send_to(&register.to_bytes(), server_address)
+                .await
+                .unwrap();
+
+            assert_eq!(
+                receive(&a).await,
+                Rendezvous::Registered {
+                    address: a.local_addr().unwrap(),
+                    peers: vec![],
+                }
+            );
+
+            b.send_to(&register.to_bytes(), server_address)
+                .await
+                .unwrap();
+
+            // Both peers should be introduced to each other at once.
+            assert_eq!(
+                receive(&a).await,
+                Rendezvous::Registered {
+                    address: a.local_addr().unwrap(),
+                    peers: vec![b.local_addr().unwrap()],
+                }
+            );
+            assert_eq!(
+                receive(&b).await,
+                Rendezvous::Registered {
+                    address: b.local_addr().unwrap(),
+                    peers: vec![a.local_addr().unwrap()],
+                }
+            );
+        });
+    }
+}
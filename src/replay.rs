@@ -0,0 +1,150 @@
+use std::collections::{HashSet, VecDeque};
+
+use crate::config::MAX_DELIVERED_IDS;
+
+/// Number of counters tracked behind the highest one, one bit each.
+const WINDOW_SIZE: u64 = 128;
+
+/// Sliding window over the message counters received from a peer, used to
+/// reject duplicated and outdated messages.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    bitmap: u128,
+    highest: Option<u64>,
+}
+
+impl ReplayWindow {
+    /// Checks that a counter has never been seen and is still within the
+    /// window.
+    pub fn check(&self, counter: u64) -> Result<(), u16> {
+        match self.highest {
+            Some(highest) if counter <= highest => {
+                let offset = highest - counter;
+
+                if offset >= WINDOW_SIZE || self.bitmap & (1 << offset) != 0 {
+                    Err(402)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Marks a counter as seen, sliding the window forward if needed. Only
+    /// counters of authenticated messages should be recorded.
+    pub fn update(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => self.bitmap |= 1 << (highest - counter),
+            Some(highest) => {
+                let shift = counter - highest;
+
+                self.bitmap = if shift >= WINDOW_SIZE {
+                    1
+                } else {
+                    (self.bitmap << shift) | 1
+                };
+                self.highest = Some(counter);
+            }
+            None => {
+                self.bitmap = 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+/// Ids of the last delivered text messages, so that retransmissions are
+/// acknowledged again without being displayed twice.
+#[derive(Debug, Default)]
+pub struct DeliveredIds {
+    ids: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl DeliveredIds {
+    /// Records an id, returning whether it was delivered for the first time.
+    /// The oldest ids are forgotten first.
+    pub fn insert(&mut self, id: u64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+
+        self.order.push_back(id);
+
+        if self.order.len() > MAX_DELIVERED_IDS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+
+        for counter in 0..3 {
+            assert_eq!(window.check(counter), Ok(()));
+            window.update(counter);
+        }
+
+        // Duplicates should be rejected.
+        assert_eq!(window.check(0), Err(402));
+        assert_eq!(window.check(2), Err(402));
+        assert_eq!(window.check(3), Ok(()));
+    }
+
+    #[test]
+    fn test_replay_window_out_of_order() {
+        let mut window = ReplayWindow::default();
+
+        window.update(10);
+
+        // Late messages within the window should be accepted once.
+        assert_eq!(window.check(5), Ok(()));
+        window.update(5);
+        assert_eq!(window.check(5), Err(402));
+        assert_eq!(window.check(6), Ok(()));
+    }
+
+    #[test]
+    fn test_replay_window_outdated() {
+        let mut window = ReplayWindow::default();
+
+        window.update(0);
+        window.update(WINDOW_SIZE);
+
+        // Counters falling behind the window should be rejected.
+        assert_eq!(window.check(0), Err(402));
+        assert_eq!(window.check(1), Ok(()));
+
+        window.update(WINDOW_SIZE * 3);
+
+        assert_eq!(window.check(WINDOW_SIZE), Err(402));
+        assert_eq!(window.check(WINDOW_SIZE * 3), Err(402));
+        assert_eq!(window.check(WINDOW_SIZE * 3 - 1), Ok(()));
+    }
+
+    #[test]
+    fn test_delivered_ids() {
+        let mut delivered_ids = DeliveredIds::default();
+
+        assert!(delivered_ids.insert(42));
+        assert!(!delivered_ids.insert(42));
+
+        for id in 0..MAX_DELIVERED_IDS as u64 {
+            delivered_ids.insert(id + 100);
+        }
+
+        // Only the most recent ids should be kept.
+        assert!(delivered_ids.insert(42));
+        assert_eq!(delivered_ids.ids.len(), MAX_DELIVERED_IDS);
+    }
+}
@@ -1,94 +1,235 @@
-use async_std::net::UdpSocket;
 use crossterm::{cursor, queue, style::Print, terminal};
 use std::{
+    collections::HashMap,
     io::{stdout, Write},
+    net::SocketAddr,
     sync::Arc,
+    time::Instant,
 };
 
 use crate::{
-    config::{BUFFER_SIZE, SERVER_PORT},
-    error::throw,
-    key::Key,
-    message::Message,
+    envelope::{Envelope, Payload},
+    error::{throw, warn, Error},
+    fragment::Reassembler,
     peers::Peers,
-    terminal::println,
-    types::SenderReceiver,
-    utils::get_content_from_buffer,
+    presence::State,
+    replay::{DeliveredIds, ReplayWindow},
+    settings::Settings,
+    terminal::{println, set_title},
+    types::{SenderReceiver, SharedNetwork, SharedPresence, SharedSessions},
 };
 
-/// Starts the UDP server based on a tuple of peers and a crypto key.
-pub async fn start(peers: Arc<Peers>, key: Arc<Key>, sender_receiver: SenderReceiver) {
-    let mut buffer = vec![0u8; BUFFER_SIZE];
-    let key = &key;
-
-    match UdpSocket::bind([peers.local.as_str(), ":", &SERVER_PORT.to_string()].join("")).await {
-        Ok(socket) => {
-            loop {
-                if let Ok(received) = socket.recv_from(&mut buffer).await {
-                    let (number_of_bytes, origin) = received;
-
-                    match Message::deserialize(get_content_from_buffer(&buffer, number_of_bytes)) {
-                        Ok(message) => {
-                            // Display the message or throw an error.
-                            match key.verify_message_signature(&message) {
-                                Ok(_) => {
-                                    let mut replay_line = None;
-                                    let mut stdout = stdout();
-
-                                    if !sender_receiver.1.is_empty() {
-                                        if let Some(line) = sender_receiver.1.recv().await.unwrap()
-                                        {
-                                            let raw_line = line.clone();
-                                            let arc_line = Arc::new(line);
-                                            let arc_cloned_line = arc_line.clone();
-
-                                            // We want to replay the line in the channel afterwards,
-                                            // store it.
-                                            replay_line = Some(arc_line);
-
-                                            // Push it back in case we need to replay it again!
-                                            sender_receiver.0.send(Some(raw_line)).await;
-
-                                            for position in 0..arc_cloned_line.length {
-                                                if position > 0 {
-                                                    queue!(stdout, cursor::MoveUp(1),).unwrap();
-                                                }
-
-                                                queue!(
-                                                    stdout,
-                                                    terminal::Clear(
-                                                        terminal::ClearType::CurrentLine
-                                                    ),
-                                                    cursor::MoveToColumn(0)
-                                                )
-                                                .unwrap();
-                                            }
-                                        }
-                                    }
-
-                                    // Display prepended peer I.P. and decrypted message.
-                                    peers.display_remote();
-                                    println(message.decrypt(key.clone()), false);
-
-                                    if let Some(line) = replay_line {
-                                        queue!(stdout, Print(&line.content)).unwrap();
-                                    }
-
-                                    stdout.flush().unwrap();
-                                }
-                                Err(_) => throw(401),
+/// Starts the receive loop based on the remote peers and their crypto
+/// sessions, replies being handed over to the client waiting for them and
+/// the presence of the peers being updated along the way.
+#[allow(clippy::too_many_arguments)]
+pub async fn start(
+    network: SharedNetwork,
+    settings: Arc<Settings>,
+    peers: Arc<Peers>,
+    sessions: SharedSessions,
+    presence: SharedPresence,
+    handshakes: Arc<HashMap<SocketAddr, String>>,
+    sender_receiver: SenderReceiver,
+    json_compatibility: bool,
+) {
+    let mut buffer = vec![0u8; settings.buffer_size];
+    let mut delivered_ids: HashMap<SocketAddr, DeliveredIds> = HashMap::new();
+    let mut reassemblers: HashMap<SocketAddr, Reassembler> = HashMap::new();
+    let mut replay_windows: HashMap<SocketAddr, ReplayWindow> = HashMap::new();
+
+    loop {
+        if let Ok(received) = network.recv_from(&mut buffer).await {
+            let (number_of_bytes, origin) = received;
+
+            match Envelope::decode(&buffer[..number_of_bytes], json_compatibility) {
+                Ok(Envelope::Message(message)) => {
+                    let counter = message.header.counter;
+                    let replay_window = replay_windows.entry(origin).or_default();
+
+                    // Reject replayed messages or messages coming from elsewhere before even
+                    // trying to open them.
+                    let opened_message = if !peers.contains(origin) {
+                        Err(Error::from(403))
+                    } else {
+                        match replay_window.check(counter) {
+                            Ok(_) => match sessions.lock().await.get_mut(&origin) {
+                                Some(session) => session.open(&message),
+                                None => Err(Error::from(403)),
+                            },
+                            Err(code) => Err(Error::from(code)),
+                        }
+                    };
+
+                    if opened_message.is_ok() {
+                        replay_window.update(counter);
+                    }
+
+                    // Fragments are only dispatched once put back together.
+                    let opened_message = match opened_message {
+                        Ok(Payload::Fragment(fragment)) => reassemblers
+                            .entry(origin)
+                            .or_default()
+                            .insert(fragment, Instant::now())
+                            .map_err(Error::from),
+                        Ok(payload) => Ok(Some(payload)),
+                        Err(error) => Err(error),
+                    };
+
+                    // Any authenticated message shows that the peer is still around.
+                    if let Ok(Some(payload)) = &opened_message {
+                        let now = Instant::now();
+                        let mut presence = presence.lock().await;
+                        let state = match payload {
+                            Payload::Disconnect => presence.leave(origin),
+                            Payload::Heartbeat { is_idle } => {
+                                presence.seen(origin, Some(*is_idle), now)
+                            }
+                            Payload::Text { .. } | Payload::Typing => {
+                                presence.seen(origin, Some(false), now)
+                            }
+                            _ => presence.seen(origin, None, now),
+                        };
+
+                        drop(presence);
+
+                        // Leaving is displayed along with the disconnection.
+                        if let Some(state) = state.filter(|state| *state != State::Left) {
+                            display_state(&peers, origin, &sender_receiver, state).await;
+                        }
+                    }
+
+                    // Dispatch on the payload kind, some of them calling for a reply.
+                    let reply = match opened_message {
+                        Ok(Some(Payload::Text { content, id })) => {
+                            // Retransmitted messages are only acknowledged again.
+                            if delivered_ids.entry(origin).or_default().insert(id) {
+                                set_title(None);
+                                display(&peers, origin, &sender_receiver, &content).await;
                             }
 
-                            match socket.send_to(&buffer[..number_of_bytes], &origin).await {
-                                Ok(_) => (),
-                                Err(_) => throw(202),
+                            Some(Payload::Ack(id))
+                        }
+                        Ok(Some(Payload::Ping(id))) => Some(Payload::Pong(id)),
+                        Ok(Some(Payload::Typing)) => {
+                            set_title(Some(&format!("{} is typing...", peers.name(origin))));
+
+                            None
+                        }
+                        Ok(Some(Payload::Disconnect)) => {
+                            set_title(None);
+                            display(&peers, origin, &sender_receiver, "left the conversation.")
+                                .await;
+
+                            None
+                        }
+                        Ok(Some(Payload::Heartbeat { .. })) => None,
+                        // Acks and pongs are answers to the client.
+                        Ok(Some(Payload::Ack(id))) | Ok(Some(Payload::Pong(id))) => {
+                            network.notify(origin, id).await;
+
+                            None
+                        }
+                        // Incomplete payloads are waiting for more fragments.
+                        Ok(Some(Payload::Fragment(_))) | Ok(None) => None,
+                        Err(error) => {
+                            warn(error);
+
+                            None
+                        }
+                    };
+
+                    if let Some(reply) = reply {
+                        let reply = sessions
+                            .lock()
+                            .await
+                            .get_mut(&origin)
+                            .map(|session| session.seal(&reply).to_bytes());
+
+                        if let Some(reply) = reply {
+                            if network.send_to(&reply, origin).await.is_err() {
+                                throw(202);
                             }
                         }
-                        Err(_) => throw(101),
                     }
                 }
+                // The peer didn't get our public key yet, send it again.
+                Ok(Envelope::KeyExchange(_)) => {
+                    if let Some(handshake) = handshakes.get(&origin) {
+                        if network.send_to(handshake.as_bytes(), origin).await.is_err() {
+                            throw(202);
+                        }
+                    }
+                }
+                Err(code) => throw(code),
+            }
+        }
+    }
+}
+
+/// Displays a new state of a remote peer above the line being typed.
+pub async fn display_state(
+    peers: &Peers,
+    remote: SocketAddr,
+    sender_receiver: &SenderReceiver,
+    state: State,
+) {
+    display(peers, remote, sender_receiver, &format!("is {}.", state)).await;
+}
+
+/// Displays some content from a remote peer above the line being typed.
+async fn display(
+    peers: &Peers,
+    remote: SocketAddr,
+    sender_receiver: &SenderReceiver,
+    content: &str,
+) {
+    // Display prepended peer I.P. and content.
+    display_above(sender_receiver, || {
+        peers.display_remote(remote);
+        println(content, false);
+    })
+    .await;
+}
+
+/// Renders something above the line being typed, which is typed again
+/// afterwards.
+pub async fn display_above(sender_receiver: &SenderReceiver, render: impl FnOnce()) {
+    let mut replay_line = None;
+    let mut stdout = stdout();
+
+    if !sender_receiver.1.is_empty() {
+        if let Some(line) = sender_receiver.1.recv().await.unwrap() {
+            let raw_line = line.clone();
+            let arc_line = Arc::new(line);
+            let arc_cloned_line = arc_line.clone();
+
+            // We want to replay the line in the channel afterwards, store it.
+            replay_line = Some(arc_line);
+
+            // Push it back in case we need to replay it again!
+            sender_receiver.0.send(Some(raw_line)).await;
+
+            for position in 0..arc_cloned_line.length {
+                if position > 0 {
+                    queue!(stdout, cursor::MoveUp(1),).unwrap();
+                }
+
+                queue!(
+                    stdout,
+                    terminal::Clear(terminal::ClearType::CurrentLine),
+                    cursor::MoveToColumn(0)
+                )
+                .unwrap();
             }
         }
-        Err(error) => eprintln!("{}", error),
     }
+
+    render();
+
+    if let Some(line) = replay_line {
+        queue!(stdout, Print(line.content.as_str())).unwrap();
+    }
+
+    stdout.flush().unwrap();
 }
@@ -0,0 +1,388 @@
+use base64::encode;
+use ring::{digest, hmac};
+use std::collections::HashMap;
+use zeroize::Zeroize;
+
+use crate::{
+    config::{MAX_SKIPPED_KEYS, MAX_SKIPPED_MESSAGES, PROTOCOL_VERSION},
+    envelope::Payload,
+    error::Error,
+    fingerprint::Fingerprint,
+    key::Key,
+    message::{Header, Message},
+    padding::Padding,
+};
+
+const AUTHENTICATION_KEY_LABEL: &[u8] = b"yume session authentication key";
+const CHAIN_KEY_LABEL: &[u8] = b"yume chain key";
+const CHAIN_KEY_STEP: &[u8] = &[0x02];
+const MESSAGE_KEY_STEP: &[u8] = &[0x01];
+
+/// KDF chain producing a fresh message key for every message, each key being
+/// erased as soon as the chain moves forward.
+#[derive(Clone)]
+struct Chain {
+    counter: u64,
+    key: [u8; digest::SHA512_OUTPUT_LEN],
+}
+
+impl Chain {
+    fn new(key: &Key, sender: &str) -> Self {
+        Chain {
+            counter: 0,
+            key: Key::derive(&key.value, &[], &[CHAIN_KEY_LABEL, sender.as_bytes()]),
+        }
+    }
+
+    /// Returns the current counter along with its message key and advances
+    /// the chain.
+    fn next(&mut self) -> (u64, Key) {
+        let chain_key = hmac::Key::new(hmac::HMAC_SHA512, &self.key);
+        let mut message_key = [0; digest::SHA512_OUTPUT_LEN];
+        let counter = self.counter;
+
+        message_key.copy_from_slice(hmac::sign(&chain_key, MESSAGE_KEY_STEP).as_ref());
+        self.key
+            .copy_from_slice(hmac::sign(&chain_key, CHAIN_KEY_STEP).as_ref());
+        self.counter += 1;
+
+        let key = Key::new(Some(message_key));
+
+        message_key.zeroize();
+
+        (counter, key)
+    }
+}
+
+impl Drop for Chain {
+    fn drop(&mut self) { self.key.zeroize(); }
+}
+
+/// Ratcheting layer sitting between the network and the messages, with one
+/// chain per direction. Both peers are identified by their ephemeral public
+/// key from the key exchange, which they agree on whatever the NATs in
+/// between.
+pub struct Session {
+    pub fingerprint: Fingerprint,
+    local: String,
+    padding: Padding,
+    receiving: Chain,
+    /// Signs every message of a direction, so that forged messages are
+    /// rejected before deriving any message key.
+    receiving_authentication: Key,
+    remote: String,
+    sending: Chain,
+    sending_authentication: Key,
+    skipped_keys: HashMap<u64, Key>,
+}
+
+impl Session {
+    pub fn new(key: &Key, local: &[u8], remote: &[u8], padding: Padding) -> Self {
+        let local = encode(local);
+        let remote = encode(remote);
+
+        Session {
+            fingerprint: Fingerprint::new(key),
+            receiving: Chain::new(key, &remote),
+            receiving_authentication: derive_authentication_key(key, &remote),
+            sending: Chain::new(key, &local),
+            sending_authentication: derive_authentication_key(key, &local),
+            local,
+            padding,
+            remote,
+            skipped_keys: HashMap::new(),
+        }
+    }
+
+    /// Encrypts a payload with the next sending message key.
+    pub fn seal(&mut self, payload: &Payload) -> Message {
+        let (counter, key) = self.sending.next();
+        let header = Header::new(
+            payload.kind(),
+            counter,
+            self.local.clone(),
+            self.remote.clone(),
+        );
+
+        let mut message = Message::new(&payload.to_bytes(), header, &key, self.padding);
+
+        message.sign(&self.sending_authentication);
+
+        message
+    }
+
+    /// Decrypts a message with its receiving message key. The receiving
+    /// chain only moves forward once the message is successfully opened.
+    pub fn open(&mut self, message: &Message) -> Result<Payload, Error> {
+        // Messages are only authentic when sent from the remote peer to us.
+        let message = &message.bind(&self.remote, &self.local);
+        let header = &message.header;
+
+        if header.version != PROTOCOL_VERSION {
+            return Err(Error::from(404));
+        }
+
+        // Checking the signature is cheap, deriving a message key far ahead isn't.
+        self.receiving_authentication
+            .verify_message_signature(message)
+            .map_err(|_| Error::from(101))?;
+
+        if header.counter < self.receiving.counter {
+            let key = self
+                .skipped_keys
+                .get(&header.counter)
+                .ok_or_else(|| Error::from(105))?;
+            let content = decrypt(message, key)?;
+
+            self.skipped_keys.remove(&header.counter);
+
+            return Ok(content);
+        }
+
+        // The messages sent while we couldn't be reached are lost for good,
+        // the chain catching up with authentic messages a bounded number of
+        // steps at a time.
+        let gap = header.counter - self.receiving.counter;
+
+        for _ in 0..gap
+            .saturating_sub(MAX_SKIPPED_MESSAGES)
+            .min(MAX_SKIPPED_MESSAGES)
+        {
+            self.receiving.next();
+        }
+
+        if header.counter - self.receiving.counter > MAX_SKIPPED_MESSAGES {
+            return Err(Error::from(105));
+        }
+
+        let mut chain = self.receiving.clone();
+        let mut skipped_keys = vec![];
+
+        loop {
+            let (counter, key) = chain.next();
+
+            if counter == header.counter {
+                let content = decrypt(message, &key)?;
+
+                self.receiving = chain;
+                self.skip(skipped_keys);
+
+                return Ok(content);
+            }
+
+            skipped_keys.push((counter, key));
+        }
+    }
+
+    /// Keeps the keys of the messages which didn't arrive yet, the oldest
+    /// ones being dropped first.
+    fn skip(&mut self, skipped_keys: Vec<(u64, Key)>) {
+        self.skipped_keys.extend(skipped_keys);
+
+        while self.skipped_keys.len() > MAX_SKIPPED_KEYS {
+            let oldest = *self.skipped_keys.keys().min().unwrap();
+
+            self.skipped_keys.remove(&oldest);
+        }
+    }
+}
+
+fn derive_authentication_key(key: &Key, sender: &str) -> Key {
+    let mut value = Key::derive(
+        &key.value,
+        &[],
+        &[AUTHENTICATION_KEY_LABEL, sender.as_bytes()],
+    );
+    let key = Key::new(Some(value));
+
+    value.zeroize();
+
+    key
+}
+
+/// Decrypts the payload of a message whose signature was already verified.
+fn decrypt(message: &Message, key: &Key) -> Result<Payload, Error> {
+    Payload::from_bytes(message.header.kind, &message.decrypt(key)?).map_err(Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Kind;
+
+    fn text(content: &str) -> Payload { Payload::text(content, 42) }
+
+    fn opened(content: &str) -> Result<Payload, Error> { Ok(text(content)) }
+
+    fn create_sessions() -> (Session, Session) {
+        let key = Key::new(None);
+        let local_public_key = [0x10; 32];
+        let remote_public_key = [0x20; 32];
+
+        (
+            Session::new(
+                &key,
+                &local_public_key,
+                &remote_public_key,
+                Padding::Buckets,
+            ),
+            Session::new(
+                &key,
+                &remote_public_key,
+                &local_public_key,
+                Padding::Buckets,
+            ),
+        )
+    }
+
+    #[test]
+    fn test_session() {
+        let (mut session_a, mut session_b) = create_sessions();
+        let message_a = session_a.seal(&text("foo"));
+        let message_b = session_a.seal(&text("bar"));
+
+        // Every message should be encrypted with a new key.
+        assert_eq!(message_a.header.counter, 0);
+        assert_eq!(message_b.header.counter, 1);
+
+        assert_eq!(session_b.open(&message_a), opened("foo"));
+        assert_eq!(session_b.open(&message_b), opened("bar"));
+
+        // Both directions should use distinct chains.
+        let message_c = session_b.seal(&text("baz"));
+
+        assert_eq!(message_c.header.counter, 0);
+        assert_ne!(message_c.signature, message_a.signature);
+        assert_eq!(session_a.open(&message_c), opened("baz"));
+
+        // Both peers should display the same fingerprint.
+        assert_eq!(session_a.fingerprint, session_b.fingerprint);
+    }
+
+    #[test]
+    fn test_session_erased_keys() {
+        let (mut session_a, mut session_b) = create_sessions();
+        let message = session_a.seal(&text("foo"));
+
+        assert_eq!(session_b.open(&message), opened("foo"));
+
+        // Once used, a message key should be gone.
+        assert_eq!(session_b.open(&message), Err(Error::from(105)));
+    }
+
+    #[test]
+    fn test_session_out_of_order() {
+        let (mut session_a, mut session_b) = create_sessions();
+        let message_a = session_a.seal(&text("foo"));
+        let message_b = session_a.seal(&text("bar"));
+        let message_c = session_a.seal(&text("baz"));
+
+        assert_eq!(session_b.open(&message_c), opened("baz"));
+        assert_eq!(session_b.open(&message_a), opened("foo"));
+        assert_eq!(session_b.open(&message_b), opened("bar"));
+        assert!(session_b.skipped_keys.is_empty());
+    }
+
+    #[test]
+    fn test_session_forged_message() {
+        let (mut session_a, mut session_b) = create_sessions();
+        let mut message = session_a.seal(&text("foo"));
+
+        message.header.counter = 42;
+
+        // A forged message should not move the receiving chain forward.
+        assert_eq!(session_b.open(&message), Err(Error::from(101)));
+        assert_eq!(session_b.receiving.counter, 0);
+        assert!(session_b.skipped_keys.is_empty());
+
+        message.header.counter = MAX_SKIPPED_MESSAGES;
+
+        // Neither should a forged message far ahead, no key being derived.
+        assert_eq!(session_b.open(&message), Err(Error::from(101)));
+        assert_eq!(session_b.receiving.counter, 0);
+        assert!(session_b.skipped_keys.is_empty());
+    }
+
+    #[test]
+    fn test_session_long_gap() {
+        let (mut session_a, mut session_b) = create_sessions();
+        let message = session_a.seal(&text("foo"));
+
+        for _ in 0..MAX_SKIPPED_MESSAGES * 5 / 2 {
+            session_a.seal(&Payload::Heartbeat { is_idle: false });
+        }
+
+        let message_a = session_a.seal(&text("bar"));
+        let message_b = session_a.seal(&text("baz"));
+
+        // The receiving chain should catch up after a long gap, a bounded
+        // number of keys being derived for every message.
+        assert_eq!(session_b.open(&message_a), Err(Error::from(105)));
+        assert_eq!(session_b.receiving.counter, MAX_SKIPPED_MESSAGES);
+        assert_eq!(session_b.open(&message_b), opened("baz"));
+        assert_eq!(session_b.open(&message_a), opened("bar"));
+
+        // The messages sent meanwhile are lost for good.
+        assert_eq!(session_b.open(&message), Err(Error::from(105)));
+    }
+
+    #[test]
+    fn test_session_metadata() {
+        let (mut session_a, mut session_b) = create_sessions();
+        let message = session_a.seal(&text("foo"));
+
+        // Messages should be bound to both peers.
+        assert_eq!(message.header.sender, session_b.remote);
+        assert_eq!(message.header.recipient, session_b.local);
+
+        // A message sent back to its sender should be rejected.
+        assert_eq!(session_a.open(&message), Err(Error::from(101)));
+
+        let mut forged_message = session_a.seal(&text("bar"));
+
+        forged_message.header.version = PROTOCOL_VERSION + 1;
+
+        assert_eq!(session_b.open(&forged_message), Err(Error::from(404)));
+
+        forged_message.header.version = PROTOCOL_VERSION;
+
+        // So should a message meant for another peer.
+        let key = Key::new(None);
+        let mut session_c = Session::new(&key, &[0x10; 32], &[0x30; 32], Padding::Buckets);
+
+        assert_eq!(
+            session_b.open(&session_c.seal(&text("baz"))),
+            Err(Error::from(101))
+        );
+        assert_eq!(session_b.open(&message), opened("foo"));
+    }
+
+    #[test]
+    fn test_session_payloads() {
+        let (mut session_a, mut session_b) = create_sessions();
+        let message = session_a.seal(&Payload::Ack(42));
+        let mut forged_message = session_a.seal(&Payload::Ping(42));
+
+        assert_eq!(message.header.kind, Kind::Ack);
+        assert_eq!(session_b.open(&message), Ok(Payload::Ack(42)));
+
+        // The payload kind should be authenticated.
+        forged_message.header.kind = Kind::Disconnect;
+
+        assert_eq!(session_b.open(&forged_message), Err(Error::from(101)));
+    }
+
+    #[test]
+    fn test_session_tampered_message() {
+        let (mut session_a, mut session_b) = create_sessions();
+        let message = session_a.seal(&text("foo"));
+        let mut tampered_message = session_a.seal(&text("bar"));
+
+        // The nonce isn't covered by the signature but is checked on decryption.
+        tampered_message.nonce[0] ^= 1;
+
+        assert_eq!(session_b.open(&tampered_message), Err(Error::from(108)));
+        assert_eq!(session_b.receiving.counter, 0);
+        assert_eq!(session_b.open(&message), opened("foo"));
+    }
+}
@@ -0,0 +1,233 @@
+use serde::Deserialize;
+use std::{fs, io::ErrorKind, path::Path, time::Duration};
+
+use crate::config::{
+    BUFFER_SIZE, HANDSHAKE_ATTEMPTS, HANDSHAKE_INTERVAL, HEARTBEAT_INTERVAL, MAX_ATTEMPTS,
+    MAX_BUFFER_SIZE, MAX_TIMEOUT, MIN_BUFFER_SIZE, RETRANSMISSION_TIMEOUT, SERVER_PORT,
+};
+
+/// Settings read from the config file or from the command line, any missing
+/// one falling back to its default value.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PartialSettings {
+    pub buffer_size: Option<usize>,
+    pub handshake_attempts: Option<u64>,
+    pub handshake_interval: Option<u64>,
+    pub heartbeat_interval: Option<u64>,
+    pub max_attempts: Option<u32>,
+    pub port: Option<u16>,
+    pub retransmission_timeout: Option<u64>,
+}
+
+impl PartialSettings {
+    /// Reads a JSON config file. A missing file is only an error if its path
+    /// was explicitly given.
+    pub fn load(path: &Path, is_explicit: bool) -> Result<Self, u16> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|_| 602),
+            Err(error) if error.kind() == ErrorKind::NotFound && !is_explicit => {
+                Ok(PartialSettings::default())
+            }
+            Err(_) => Err(601),
+        }
+    }
+
+    /// Overrides these settings with the ones set in another source.
+    pub fn merge(self, other: PartialSettings) -> Self {
+        PartialSettings {
+            buffer_size: other.buffer_size.or(self.buffer_size),
+            handshake_attempts: other.handshake_attempts.or(self.handshake_attempts),
+            handshake_interval: other.handshake_interval.or(self.handshake_interval),
+            heartbeat_interval: other.heartbeat_interval.or(self.heartbeat_interval),
+            max_attempts: other.max_attempts.or(self.max_attempts),
+            port: other.port.or(self.port),
+            retransmission_timeout: other.retransmission_timeout.or(self.retransmission_timeout),
+        }
+    }
+}
+
+/// Validated network settings.
+#[derive(Debug, PartialEq)]
+pub struct Settings {
+    pub buffer_size: usize,
+    pub handshake_attempts: u64,
+    pub handshake_interval: Duration,
+    /// Time between two heartbeats, peers missing several of them being
+    /// considered unreachable.
+    pub heartbeat_interval: Duration,
+    pub max_attempts: u32,
+    /// Default port of both peers, unless given along with their address.
+    pub port: u16,
+    pub retransmission_timeout: Duration,
+}
+
+impl Settings {
+    pub fn new(settings: PartialSettings) -> Result<Self, u16> {
+        let buffer_size = settings.buffer_size.unwrap_or(BUFFER_SIZE);
+        let handshake_attempts = settings.handshake_attempts.unwrap_or(HANDSHAKE_ATTEMPTS);
+        let handshake_interval = settings.handshake_interval.unwrap_or(HANDSHAKE_INTERVAL);
+        let heartbeat_interval = settings.heartbeat_interval.unwrap_or(HEARTBEAT_INTERVAL);
+        let max_attempts = settings.max_attempts.unwrap_or(MAX_ATTEMPTS);
+        let port = settings.port.unwrap_or(SERVER_PORT);
+        let retransmission_timeout = settings
+            .retransmission_timeout
+            .unwrap_or(RETRANSMISSION_TIMEOUT);
+
+        if !(MIN_BUFFER_SIZE..=MAX_BUFFER_SIZE).contains(&buffer_size) {
+            return Err(604);
+        }
+
+        if port == 0 {
+            return Err(605);
+        }
+
+        if handshake_attempts == 0
+            || handshake_interval == 0
+            || heartbeat_interval == 0
+            || max_attempts == 0
+            || retransmission_timeout == 0
+        {
+            return Err(606);
+        }
+
+        // Longer timeouts would overflow once multiplied or added to an instant.
+        if handshake_interval > MAX_TIMEOUT
+            || heartbeat_interval > MAX_TIMEOUT
+            || retransmission_timeout > MAX_TIMEOUT
+        {
+            return Err(613);
+        }
+
+        Ok(Settings {
+            buffer_size,
+            handshake_attempts,
+            handshake_interval: Duration::from_secs(handshake_interval),
+            heartbeat_interval: Duration::from_secs(heartbeat_interval),
+            max_attempts,
+            port,
+            retransmission_timeout: Duration::from_secs(retransmission_timeout),
+        })
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self { Settings::new(PartialSettings::default()).unwrap() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_settings() {
+        let settings = Settings::default();
+
+        assert_eq!(settings.buffer_size, BUFFER_SIZE);
+        assert_eq!(settings.port, SERVER_PORT);
+
+        // The command line should take precedence over the config file.
+        let file_settings: PartialSettings =
+            serde_json::from_str(r#"{ "port": 4000, "max-attempts": 2 }"#).unwrap();
+        let settings = Settings::new(file_settings.merge(PartialSettings {
+            port: Some(5000),
+            ..PartialSettings::default()
+        }))
+        .unwrap();
+
+        assert_eq!(settings.max_attempts, 2);
+        assert_eq!(settings.port, 5000);
+    }
+
+    #[test]
+    fn test_settings_validation() {
+        let invalid_settings = vec![
+            (
+                PartialSettings {
+                    buffer_size: Some(MIN_BUFFER_SIZE - 1),
+                    ..PartialSettings::default()
+                },
+                604,
+            ),
+            (
+                PartialSettings {
+                    buffer_size: Some(MAX_BUFFER_SIZE + 1),
+                    ..PartialSettings::default()
+                },
+                604,
+            ),
+            (
+                PartialSettings {
+                    port: Some(0),
+                    ..PartialSettings::default()
+                },
+                605,
+            ),
+            (
+                PartialSettings {
+                    heartbeat_interval: Some(0),
+                    ..PartialSettings::default()
+                },
+                606,
+            ),
+            (
+                PartialSettings {
+                    retransmission_timeout: Some(0),
+                    ..PartialSettings::default()
+                },
+                606,
+            ),
+            (
+                PartialSettings {
+                    handshake_interval: Some(u64::MAX),
+                    ..PartialSettings::default()
+                },
+                613,
+            ),
+            (
+                PartialSettings {
+                    heartbeat_interval: Some(MAX_TIMEOUT + 1),
+                    ..PartialSettings::default()
+                },
+                613,
+            ),
+            (
+                PartialSettings {
+                    retransmission_timeout: Some(u64::MAX),
+                    ..PartialSettings::default()
+                },
+                613,
+            ),
+        ];
+
+        for (settings, code) in invalid_settings {
+            assert_eq!(Settings::new(settings), Err(code));
+        }
+    }
+
+    #[test]
+    fn test_settings_file() {
+        let path = env::temp_dir().join(format!("yume-settings-{}.json", std::process::id()));
+
+        // Only an explicitly given config file has to exist.
+        assert_eq!(
+            PartialSettings::load(&path, false),
+            Ok(PartialSettings::default())
+        );
+        assert_eq!(PartialSettings::load(&path, true), Err(601));
+
+        fs::write(&path, r#"{ "prot": 4000 }"#).unwrap();
+
+        assert_eq!(PartialSettings::load(&path, true), Err(602));
+
+        fs::write(&path, r#"{ "buffer-size": 4096 }"#).unwrap();
+
+        assert_eq!(
+            PartialSettings::load(&path, true).map(|settings| settings.buffer_size),
+            Ok(Some(4096))
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use async_std::{io, net::UdpSocket};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
+
+/// Binds a UDP socket to the given address. The unspecified IPv6 address
+/// makes for a dual-stack socket, whatever the system default.
+pub fn bind(address: SocketAddr) -> io::Result<UdpSocket> {
+    let domain = match address {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::dgram(), Some(Protocol::udp()))?;
+
+    if address.is_ipv6() && address.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+
+    socket.bind(&SockAddr::from(address))?;
+
+    Ok(UdpSocket::from(socket.into_udp_socket()))
+}
+
+/// Sends a datagram, an IPv4 address being mapped to an IPv6 one for a
+/// dual-stack socket.
+pub async fn send_to(socket: &UdpSocket, bytes: &[u8], address: SocketAddr) -> io::Result<usize> {
+    let address = match (socket.local_addr()?, address) {
+        (SocketAddr::V6(_), SocketAddr::V4(address)) => {
+            SocketAddr::new(IpAddr::V6(address.ip().to_ipv6_mapped()), address.port())
+        }
+        (_, address) => address,
+    };
+
+    socket.send_to(bytes, address).await
+}
+
+/// Both peers need to agree on the textual representation of an address,
+/// IPv4-mapped IPv6 addresses coming from dual-stack sockets being turned
+/// back into IPv4 ones. The port and the zone ID are kept.
+pub fn canonicalize_address(address: SocketAddr) -> SocketAddr {
+    match address {
+        SocketAddr::V6(v6) => v6
+            .ip()
+            .to_ipv4_mapped()
+            .map_or(address, |ip| SocketAddr::new(IpAddr::V4(ip), v6.port())),
+        address => address,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    #[test]
+    fn test_bind() {
+        task::block_on(async {
+            let socket = bind("[::]:0".parse().unwrap()).unwrap();
+            let port = socket.local_addr().unwrap().port();
+            let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut buffer = [0; 8];
+
+            // IPv4 peers should reach the unspecified IPv6 address.
+            other.send_to(b"yume", ("127.0.0.1", port)).await.unwrap();
+
+            let (number_of_bytes, _) = socket.recv_from(&mut buffer).await.unwrap();
+
+            assert_eq!(&buffer[..number_of_bytes], b"yume");
+        });
+    }
+}
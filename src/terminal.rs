@@ -5,7 +5,14 @@ use crossterm::{
     style::Print,
     terminal,
 };
-use std::io::{stdout, Write};
+use std::{
+    fmt::Display,
+    io::{stdout, Write},
+};
+use zeroize::Zeroizing;
+
+const LINE_CAPACITY: usize = 1024;
+const MASK_CHARACTER: char = '•';
 
 pub fn enter_secondary_screen() {
     terminal::enable_raw_mode().unwrap();
@@ -13,7 +20,21 @@ pub fn enter_secondary_screen() {
     execute!(stdout(), terminal::EnterAlternateScreen,).unwrap();
 }
 
-pub fn println(line: String, clear_line: bool) {
+pub fn enter_raw_mode() { terminal::enable_raw_mode().unwrap(); }
+
+pub fn leave_raw_mode() { terminal::disable_raw_mode().unwrap(); }
+
+/// Sets the terminal title, some status being optionally appended.
+pub fn set_title(status: Option<&str>) {
+    let title = match status {
+        Some(status) => format!("yume - {}", status),
+        None => String::from("yume"),
+    };
+
+    execute!(stdout(), terminal::SetTitle(&title)).unwrap();
+}
+
+pub fn println<T: Display + Clone>(line: T, clear_line: bool) {
     let mut stdout = stdout();
 
     if clear_line {
@@ -25,18 +46,38 @@ pub fn println(line: String, clear_line: bool) {
         .unwrap();
     }
 
-    queue!(
-        stdout,
-        Print(format!("{}\n", line)),
-        cursor::MoveToColumn(0),
-    )
-    .unwrap();
+    queue!(stdout, Print(line), Print("\n"), cursor::MoveToColumn(0),).unwrap();
 
     stdout.flush().unwrap();
 }
 
-pub fn prompt(question: Option<String>) -> Result<String, String> {
-    let mut characters = String::new();
+pub fn prompt(question: Option<String>) -> Result<String, String> { read_line(question, false) }
+
+/// Prompts for a secret without echoing it, every typed or pasted character
+/// being masked. The answer is wiped once dropped.
+pub fn prompt_secret(question: Option<String>) -> Result<Zeroizing<String>, String> {
+    read_line(question, true).map(Zeroizing::new)
+}
+
+/// Asks a yes/no question, anything but "y" or "yes" being a no.
+pub fn confirm(question: String) -> Result<bool, String> {
+    prompt(Some(format!("{} [y/N]", question)))
+        .map(|answer| matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+fn read_line(question: Option<String>, is_masked: bool) -> Result<String, String> {
+    // Pasted content comes in as a burst of characters, make room for it
+    // upfront so that a secret is never left behind by a reallocation.
+    let mut characters = String::with_capacity(LINE_CAPACITY);
+    let render = |characters: &String| {
+        if is_masked {
+            MASK_CHARACTER
+                .to_string()
+                .repeat(characters.chars().count())
+        } else {
+            characters.clone()
+        }
+    };
 
     if let Some(text) = question {
         println(text, true);
@@ -51,7 +92,11 @@ pub fn prompt(question: Option<String>) -> Result<String, String> {
             }
             KeyCode::Char(character) => {
                 characters.push(character);
-                execute!(stdout(), Print(character)).unwrap();
+                execute!(
+                    stdout(),
+                    Print(if is_masked { MASK_CHARACTER } else { character })
+                )
+                .unwrap();
             }
             KeyCode::Backspace => {
                 characters.pop();
@@ -59,7 +104,7 @@ pub fn prompt(question: Option<String>) -> Result<String, String> {
                     stdout(),
                     terminal::Clear(terminal::ClearType::CurrentLine),
                     cursor::MoveToColumn(0),
-                    Print(characters.clone()),
+                    Print(render(&characters)),
                 )
                 .unwrap();
             }
@@ -1,5 +1,9 @@
-use crate::io::Line;
-use async_std::sync::{Receiver, Sender};
-use std::sync::Arc;
+use crate::{io::Line, network::Network, presence::Presence, session::Session};
+use async_std::sync::{Mutex, Receiver, Sender};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
 pub type SenderReceiver = Arc<(Sender<Option<Line>>, Receiver<Option<Line>>)>;
+pub type SharedNetwork = Arc<Network>;
+pub type SharedPresence = Arc<Mutex<Presence>>;
+/// Sessions with every remote peer.
+pub type SharedSessions = Arc<Mutex<HashMap<SocketAddr, Session>>>;
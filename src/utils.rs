@@ -1,4 +1,5 @@
 use ring::{digest, rand};
+use std::{convert::TryInto, env, path::PathBuf};
 
 /// Generates a random array of constant size 64.
 pub fn generate_random_array() -> [u8; digest::SHA512_OUTPUT_LEN] {
@@ -8,12 +9,26 @@ pub fn generate_random_array() -> [u8; digest::SHA512_OUTPUT_LEN] {
     random_array
 }
 
+/// Generates a random identifier.
+pub fn generate_random_id() -> u64 {
+    u64::from_be_bytes(generate_random_array()[..8].try_into().unwrap())
+}
+
+/// Returns a path relative to the home directory of the user.
+pub fn get_home_path(path: &str) -> PathBuf {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map_or_else(PathBuf::new, PathBuf::from)
+        .join(path)
+}
+
 /// Returns a string out of a buffer for a given number of bytes.
 pub fn get_content_from_buffer(buffer: &[u8], number_of_bytes: usize) -> String {
     String::from_utf8_lossy(&buffer[..number_of_bytes]).to_string()
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod utils {
     use super::*;
 
@@ -31,6 +46,6 @@ mod utils {
         let cat = "cat";
         let a: &[u8] = cat.as_ref();
 
-        assert_eq!(get_content_from_buffer(&a[..], 3), cat);
+        assert_eq!(get_content_from_buffer(a, 3), cat);
     }
 }
@@ -0,0 +1,141 @@
+use std::convert::TryInto;
+
+/// Leading bytes of every binary envelope.
+pub const MAGIC: &[u8] = b"YUME";
+pub const WIRE_VERSION: u8 = 1;
+
+const HEADER_LENGTH: usize = MAGIC.len() + 1 + LENGTH_PREFIX;
+const LENGTH_PREFIX: usize = 4;
+
+/// Builds a binary envelope: the magic bytes and the wire version followed
+/// by the length of the typed fields, each field being a tag, a length and a
+/// value.
+#[derive(Default)]
+pub struct Encoder {
+    fields: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn field(mut self, tag: u8, value: &[u8]) -> Self {
+        self.fields.push(tag);
+        self.fields
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.fields.extend_from_slice(value);
+
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH + self.fields.len());
+
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(WIRE_VERSION);
+        bytes.extend_from_slice(&(self.fields.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.fields);
+
+        bytes
+    }
+}
+
+/// Typed fields of a decoded envelope.
+#[derive(Debug, PartialEq)]
+pub struct Fields<'a>(Vec<(u8, &'a [u8])>);
+
+impl<'a> Fields<'a> {
+    /// Returns the value of a field, which must appear exactly once.
+    pub fn get(&self, tag: u8) -> Result<&'a [u8], u16> {
+        let mut values = self.0.iter().filter(|(field_tag, _)| *field_tag == tag);
+
+        match (values.next(), values.next()) {
+            (Some((_, value)), None) => Ok(value),
+            _ => Err(401),
+        }
+    }
+
+    pub fn get_array<const N: usize>(&self, tag: u8) -> Result<[u8; N], u16> {
+        self.get(tag)?.try_into().map_err(|_| 401)
+    }
+}
+
+/// Checks whether some bytes look like a binary envelope.
+pub fn is_envelope(bytes: &[u8]) -> bool { bytes.starts_with(MAGIC) }
+
+/// Splits an envelope into its typed fields, truncated or trailing bytes
+/// being rejected.
+pub fn decode(bytes: &[u8]) -> Result<Fields<'_>, u16> {
+    if bytes.len() < HEADER_LENGTH || !is_envelope(bytes) {
+        return Err(401);
+    }
+
+    if bytes[MAGIC.len()] != WIRE_VERSION {
+        return Err(404);
+    }
+
+    let (length, mut rest) = bytes[MAGIC.len() + 1..].split_at(LENGTH_PREFIX);
+
+    if u32::from_be_bytes(length.try_into().unwrap()) as usize != rest.len() {
+        return Err(401);
+    }
+
+    let mut fields = vec![];
+
+    while !rest.is_empty() {
+        if rest.len() < 1 + LENGTH_PREFIX {
+            return Err(401);
+        }
+
+        let (tag, length) = (rest[0], &rest[1..1 + LENGTH_PREFIX]);
+        let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+        let value = rest[1 + LENGTH_PREFIX..].get(..length).ok_or(401_u16)?;
+
+        fields.push((tag, value));
+        rest = &rest[1 + LENGTH_PREFIX + length..];
+    }
+
+    Ok(Fields(fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wire() {
+        let bytes = Encoder::default().field(1, &[42]).field(2, b"foo").finish();
+
+        assert_eq!(
+            bytes,
+            vec![
+                b'Y', b'U', b'M', b'E', 1, 0, 0, 0, 14, 1, 0, 0, 0, 1, 42, 2, 0, 0, 0, 3, b'f',
+                b'o', b'o'
+            ]
+        );
+
+        let fields = decode(&bytes).unwrap();
+
+        assert_eq!(fields.get(1), Ok(&[42][..]));
+        assert_eq!(fields.get_array(2), Ok(*b"foo"));
+        assert_eq!(fields.get(3), Err(401));
+    }
+
+    #[test]
+    fn test_wire_malformed() {
+        let bytes = Encoder::default().field(1, b"foo").finish();
+        let mut wrong_version = bytes.clone();
+
+        wrong_version[MAGIC.len()] += 1;
+
+        assert_eq!(decode(&bytes[..bytes.len() - 1]), Err(401));
+        assert_eq!(decode(&[&bytes[..], &[0]].concat()), Err(401));
+        assert_eq!(decode(&bytes[1..]), Err(401));
+        assert_eq!(decode(&wrong_version), Err(404));
+
+        // Duplicated fields are ambiguous.
+        let bytes = Encoder::default()
+            .field(1, b"foo")
+            .field(1, b"bar")
+            .finish();
+
+        assert_eq!(decode(&bytes).unwrap().get(1), Err(401));
+    }
+}